@@ -0,0 +1,183 @@
+//! OAuth Redirect Listener
+//!
+//! A minimal HTTP listener on the local redirect URI. Spotify redirects the browser there after
+//! the login, so the authorization code can be picked up without copying the URL by hand.
+
+use crate::login::AuthenticationError;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SUCCESS_PAGE: &str = "<html><body><h1>Successfully logged in!</h1>\
+                            <p>You can close this window and return to the terminal.</p></body></html>";
+const FAILURE_PAGE: &str = "<html><body><h1>Login failed!</h1>\
+                            <p>Please return to the terminal for details.</p></body></html>";
+
+pub struct Listener {
+    listener: TcpListener,
+    path: String,
+}
+
+impl Listener {
+    /// Starts listening on the address of the redirect URI.
+    /// Returns `None` if the URI doesn't point to this machine.
+    pub fn bind(redirect_uri: &str) -> Option<Result<Listener, AuthenticationError>> {
+        let (address, path) = local_address(redirect_uri)?;
+        Some(
+            TcpListener::bind(address.as_str())
+                .map(|listener| Listener { listener, path })
+                .map_err(AuthenticationError::from),
+        )
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, AuthenticationError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Waits for the redirect and returns the authorization code.
+    /// Stray requests and redirects with a foreign `state` are answered and ignored, so only
+    /// a denied login or the timeout end the wait without a code.
+    pub fn wait_for_code(
+        &self,
+        state: &str,
+        timeout: Duration,
+    ) -> Result<String, AuthenticationError> {
+        let deadline = Instant::now() + timeout;
+        self.listener.set_nonblocking(true)?;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match self.handle(stream, state) {
+                    Ok(Some(result)) => return result,
+                    Ok(None) => {}
+                    Err(err) => {
+                        eprintln!("Ignoring a broken connection on the redirect URI: {}", err)
+                    }
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(AuthenticationError::CallbackError(format!(
+                            "Didn't receive the login redirect within {} seconds",
+                            timeout.as_secs()
+                        )));
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Handles a single request, returns `None` for requests that aren't the redirect of this login
+    fn handle(
+        &self,
+        stream: TcpStream,
+        state: &str,
+    ) -> Result<Option<Result<String, AuthenticationError>>, std::io::Error> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if path != self.path {
+            respond(stream, "404 Not Found", "")?;
+            return Ok(None);
+        }
+
+        let params = parse_query(query);
+        if params.get("state").map(String::as_str) != Some(state) {
+            eprintln!("Ignoring a login redirect with a foreign state");
+            respond(stream, "400 Bad Request", FAILURE_PAGE)?;
+            return Ok(None);
+        }
+
+        let result = if let Some(error) = params.get("error") {
+            Err(AuthenticationError::CallbackError(format!(
+                "Spotify denied the login: {}",
+                error
+            )))
+        } else {
+            match params.get("code") {
+                Some(code) => Ok(code.clone()),
+                None => {
+                    eprintln!("Ignoring a login redirect without a code");
+                    respond(stream, "400 Bad Request", FAILURE_PAGE)?;
+                    return Ok(None);
+                }
+            }
+        };
+
+        match result {
+            Ok(_) => respond(stream, "200 OK", SUCCESS_PAGE)?,
+            Err(_) => respond(stream, "400 Bad Request", FAILURE_PAGE)?,
+        }
+        Ok(Some(result))
+    }
+}
+
+fn respond(mut stream: TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Splits a local `http://` redirect URI into the socket address to bind and the path
+fn local_address(redirect_uri: &str) -> Option<(String, String)> {
+    let rest = redirect_uri.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>().ok()?),
+        _ => (authority, 80),
+    };
+    let host = match host {
+        "localhost" | "127.0.0.1" => "127.0.0.1",
+        "[::1]" => "[::1]",
+        _ => return None,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or("/");
+    Some((format!("{}:{}", host, port), path.to_string()))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+/// Decodes a form url encoded query component
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' if idx + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        idx += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,231 @@
+//! Change Reporters
+//!
+//! [`crate::compare`] tells a [`ChangeReporter`] about every change it finds, so it doesn't
+//! depend on how (or if) the changes are shown. The progress bars of the command line use
+//! [`ProgressReporter`], logs use [`LogReporter`], dry runs use [`NoopReporter`] and tests can
+//! inspect the changes with [`CollectingReporter`].
+
+use crate::types::PlaylistItem;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The playlist a change belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subject<'a> {
+    /// The user the playlist was fetched for, or its owner
+    pub label: &'a str,
+    pub id: &'a str,
+    /// The stored name, the new one for created playlists
+    pub name: &'a str,
+}
+
+/// Receives the changes of a playlist. Every callback does nothing by default, so a reporter
+/// only implements the changes it is interested in. Playlists are compared in parallel, the
+/// callbacks of different playlists can interleave.
+pub trait ChangeReporter: Sync {
+    fn created(&self, _playlist: &Subject) {}
+    fn renamed(&self, _playlist: &Subject, _from: &str, _to: &str) {}
+    fn description_changed(&self, _playlist: &Subject, _from: Option<&str>, _to: Option<&str>) {}
+    fn tracks_added(&self, _playlist: &Subject, _items: &[PlaylistItem]) {}
+    fn tracks_removed(&self, _playlist: &Subject, _items: &[PlaylistItem]) {}
+    fn availability_changed(&self, _playlist: &Subject, _items: &[PlaylistItem]) {}
+}
+
+/// Ignores every change, e.g. for dry runs that describe the changes on their own
+pub struct NoopReporter;
+
+impl ChangeReporter for NoopReporter {}
+
+/// One line per change, shared by the reporters that print
+fn line(playlist: &Subject, what: &str) -> String {
+    format!(
+        "[{}] {} {} ( {} )",
+        playlist.label, what, playlist.name, playlist.id
+    )
+}
+
+/// Reporters that print a line per change
+trait Lines {
+    fn println(&self, line: String);
+}
+
+impl<T: Lines + Sync> ChangeReporter for T {
+    fn created(&self, playlist: &Subject) {
+        self.println(line(playlist, "Created"));
+    }
+
+    fn renamed(&self, playlist: &Subject, _from: &str, to: &str) {
+        self.println(format!("{} to {}", line(playlist, "Renamed"), to));
+    }
+
+    fn description_changed(&self, playlist: &Subject, _from: Option<&str>, _to: Option<&str>) {
+        self.println(line(playlist, "Updated description for"));
+    }
+
+    fn tracks_added(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        let what = format!("Added {} tracks to", items.len());
+        self.println(line(playlist, &what));
+    }
+
+    fn tracks_removed(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        let what = format!("Removed {} tracks from", items.len());
+        self.println(line(playlist, &what));
+    }
+
+    fn availability_changed(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        let what = format!("Changed availability of {} tracks in", items.len());
+        self.println(line(playlist, &what));
+    }
+}
+
+/// Prints the changes above the progress bars
+pub struct ProgressReporter {
+    multi: indicatif::MultiProgress,
+}
+
+impl ProgressReporter {
+    pub fn new(multi: &indicatif::MultiProgress) -> ProgressReporter {
+        ProgressReporter {
+            multi: multi.clone(),
+        }
+    }
+}
+
+impl Lines for ProgressReporter {
+    fn println(&self, line: String) {
+        // Nothing is drawn for hidden progress bars, e.g. in the quiet mode
+        let _ = self.multi.println(line);
+    }
+}
+
+/// Writes a line per change, e.g. to stdout for logs
+pub struct LogReporter<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> LogReporter<W> {
+    pub fn new(out: W) -> LogReporter<W> {
+        LogReporter {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<W: Write + Send> Lines for LogReporter<W> {
+    fn println(&self, line: String) {
+        let mut out = self.out.lock().unwrap_or_else(|err| err.into_inner());
+        // A closed log shouldn't stop the run
+        let _ = writeln!(out, "{}", line);
+    }
+}
+
+/// A change as it is kept by [`CollectingReporter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created {
+        id: String,
+        name: String,
+    },
+    Renamed {
+        id: String,
+        from: String,
+        to: String,
+    },
+    DescriptionChanged {
+        id: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    TracksAdded {
+        id: String,
+        items: Vec<PlaylistItem>,
+    },
+    TracksRemoved {
+        id: String,
+        items: Vec<PlaylistItem>,
+    },
+    AvailabilityChanged {
+        id: String,
+        items: Vec<PlaylistItem>,
+    },
+}
+
+/// Keeps every change, in the order they were reported
+#[derive(Debug, Default)]
+pub struct CollectingReporter {
+    changes: Mutex<Vec<Change>>,
+}
+
+impl CollectingReporter {
+    pub fn new() -> CollectingReporter {
+        CollectingReporter::default()
+    }
+
+    pub fn changes(&self) -> Vec<Change> {
+        self.lock().clone()
+    }
+
+    pub fn into_changes(self) -> Vec<Change> {
+        self.changes
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Change>> {
+        self.changes.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn push(&self, change: Change) {
+        self.lock().push(change);
+    }
+}
+
+impl ChangeReporter for CollectingReporter {
+    fn created(&self, playlist: &Subject) {
+        self.push(Change::Created {
+            id: playlist.id.to_string(),
+            name: playlist.name.to_string(),
+        });
+    }
+
+    fn renamed(&self, playlist: &Subject, from: &str, to: &str) {
+        self.push(Change::Renamed {
+            id: playlist.id.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+
+    fn description_changed(&self, playlist: &Subject, from: Option<&str>, to: Option<&str>) {
+        self.push(Change::DescriptionChanged {
+            id: playlist.id.to_string(),
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+        });
+    }
+
+    fn tracks_added(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        self.push(Change::TracksAdded {
+            id: playlist.id.to_string(),
+            items: items.to_vec(),
+        });
+    }
+
+    fn tracks_removed(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        self.push(Change::TracksRemoved {
+            id: playlist.id.to_string(),
+            items: items.to_vec(),
+        });
+    }
+
+    fn availability_changed(&self, playlist: &Subject, items: &[PlaylistItem]) {
+        self.push(Change::AvailabilityChanged {
+            id: playlist.id.to_string(),
+            items: items.to_vec(),
+        });
+    }
+}
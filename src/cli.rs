@@ -0,0 +1,270 @@
+//! Command Line Interface
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[command(
+    name = "spt",
+    version,
+    about = "Track Spotify playlists with an event sourced history",
+    after_help = "Without a command `spt run` is executed."
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// The command to execute, `run` if none is given
+    pub fn command(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or(Command::Run(RunArgs::default()))
+    }
+}
+
+/// Options available for every command
+#[derive(Args, Debug, Clone, Default)]
+pub struct GlobalArgs {
+    /// Profile to use, `all` runs every profile one after another
+    #[arg(short, long, global = true, env = "SPT_PROFILE", value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Run every profile one after another
+    #[arg(long, global = true, conflicts_with = "profile")]
+    pub all_profiles: bool,
+
+    /// Directory with the users and events [default: data]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Config file [default: spt.toml]
+    #[arg(short, long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// OAuth credentials file [default: credentials.json]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub credentials: Option<PathBuf>,
+
+    /// Print more details, e.g. unchanged playlists
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print changes and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// How `run` and `watch` report progress, `diff` prints JSON in the json mode [default: tty
+    /// on a terminal, plain otherwise]
+    #[arg(
+        long,
+        global = true,
+        env = "SPT_OUTPUT",
+        value_enum,
+        value_name = "MODE"
+    )]
+    pub output: Option<OutputMode>,
+}
+
+/// How runs are reported
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    /// Progress bars and change lines
+    Tty,
+    /// Change lines only, for logs
+    Plain,
+    /// A JSON report of every run on stdout, messages on stderr
+    Json,
+}
+
+impl OutputMode {
+    /// `tty` if stderr is a terminal, `plain` otherwise (e.g. in cron jobs)
+    pub fn detect() -> OutputMode {
+        use std::io::IsTerminal;
+
+        match std::io::stderr().is_terminal() {
+            true => OutputMode::Tty,
+            false => OutputMode::Plain,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Fetch the playlists of all users and the tracked playlists and record the changes
+    Run(RunArgs),
+    /// Keep running and fetch the users and playlists on a schedule
+    Watch(WatchArgs),
+    /// Manage the tracked users
+    #[command(subcommand)]
+    Users(UsersCommand),
+    /// Manage the playlists tracked on their own
+    #[command(subcommand)]
+    Playlists(PlaylistsCommand),
+    /// List the events of a playlist with a summary of their changes
+    History(HistoryArgs),
+    /// Print the stored state of a playlist
+    Show(ShowArgs),
+    /// Show what changed in a playlist since it is tracked, or between two points in time
+    Diff(DiffArgs),
+    /// Export the stored playlists as JSON
+    Export(ExportArgs),
+    /// Check that every playlist can be rebuilt from its events
+    Verify,
+    /// Print statistics about the stored events
+    Stats,
+    /// List the unavailable tracks of every playlist
+    Unavailable,
+    /// Log in interactively and export the token for headless runs
+    Auth(AuthArgs),
+    /// Check or print the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct RunArgs {
+    /// Only update these users (id, URI or display name), skips the tracked playlists
+    #[arg(short, long = "user", value_name = "USER")]
+    pub users: Vec<String>,
+    /// Show the changes that would be recorded without saving any events
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct WatchArgs {
+    /// Interval for users and playlists without their own, e.g. 30s, 15m or 2h [default: 1h]
+    #[arg(short, long, value_parser = crate::schedule::parse_interval)]
+    pub interval: Option<std::time::Duration>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum UsersCommand {
+    /// Add a user to track
+    Add {
+        /// Spotify id or URI of the user
+        id: String,
+        /// Name shown in the output instead of the id
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Stop tracking a user, the recorded events are kept
+    Remove {
+        /// Id, URI or display name of the user
+        user: String,
+    },
+    /// Change the name shown for a user
+    Rename {
+        /// Id, URI or display name of the user
+        user: String,
+        /// The new name
+        name: String,
+    },
+    /// List the tracked users
+    List,
+    /// Check every user against Spotify, fill in missing names and drop duplicates
+    Validate {
+        /// Also remove users that don't exist
+        #[arg(long)]
+        remove_invalid: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PlaylistsCommand {
+    /// Track a playlist independent of its owner
+    Add {
+        /// Id, URI or link of the playlist
+        playlist: String,
+        /// Name shown in the list instead of the id
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Market used to fetch the playlist, overrides the global one
+        #[arg(short, long)]
+        market: Option<String>,
+        /// How often `spt watch` fetches the playlist, e.g. 30m
+        #[arg(short, long)]
+        interval: Option<String>,
+    },
+    /// Stop tracking a playlist, the recorded events are kept
+    Remove {
+        /// Id, URI, link or name of the playlist
+        playlist: String,
+    },
+    /// List the tracked playlists
+    List,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct HistoryArgs {
+    /// Id, URI, link or name of the playlist
+    pub playlist: String,
+    /// Only list events of this type, e.g. AddedTracks or just added. Can be given more than
+    /// once.
+    #[arg(short = 't', long = "type", value_name = "TYPE", value_parser = crate::query::parse_event_type)]
+    pub types: Vec<&'static str>,
+    /// Only list events recorded at or after this time, same formats as `show --at`
+    #[arg(long, value_name = "TIME", value_parser = crate::query::parse_time)]
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only list events recorded at or before this time
+    #[arg(long, value_name = "TIME", value_parser = crate::query::parse_time)]
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    /// List at most this many events
+    #[arg(short = 'n', long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// Skip this many of the matching events
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub offset: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ShowArgs {
+    /// Id, URI, link or name of the playlist
+    pub playlist: String,
+    /// Show the playlist as it was at this time, e.g. 2024-05-01, "2024-05-01 18:00" (UTC),
+    /// 2024-05-01T18:00:00+02:00 or 30d for 30 days ago
+    #[arg(long, value_name = "TIME", value_parser = crate::query::parse_time)]
+    pub at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DiffArgs {
+    /// Id, URI, link or name of the playlist
+    pub playlist: String,
+    /// Compare the state at this time instead of the first recorded one, same formats as
+    /// `show --at`
+    #[arg(long, value_name = "TIME", value_parser = crate::query::parse_time)]
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Compare with the state at this time instead of the current one
+    #[arg(long, value_name = "TIME", value_parser = crate::query::parse_time)]
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Only export this playlist (id, URI, link or name)
+    pub playlist: Option<String>,
+    /// Write to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AuthArgs {
+    /// Also save the token to this file
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// Validate the config file and the files it points to
+    Check,
+    /// Print the effective configuration, including defaults and overrides
+    Show,
+}
@@ -0,0 +1,248 @@
+//! Configuration
+//!
+//! All settings of a profile can be collected in `spt.toml` (`profiles/<name>/spt.toml` for
+//! named profiles, or the file given with `--config`). Every section is optional, environment
+//! variables and command line options take precedence over the file. Relative paths are
+//! resolved against the directory of the file.
+
+use crate::cli::OutputMode;
+use crate::filter::PlaylistFilter;
+use crate::login::{EnvScope, OAuthConfig};
+use crate::notify::Sink;
+use crate::query;
+use crate::schedule;
+use crate::types::{self, SPTError, TrackedPlaylist, TrackedUser};
+use rspotify::model;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_MAIN_STYLE: &str =
+    "[{elapsed_precise}][{bar:40.green/white}][{pos:>3}/{len:3}]: {msg}";
+pub const DEFAULT_LOWER_STYLE: &str = "          [{bar:40.green/white}][{pos:>3}/{len:3}]: {msg}";
+pub const DEFAULT_PROGRESS_CHARS: &str = "=>-";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub paths: Paths,
+    /// OAuth settings, used instead of the credentials file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<OAuthConfig>,
+    pub fetch: Fetch,
+    pub watch: Watch,
+    pub progress: Progress,
+    pub output: Output,
+    /// Global playlist filter, applied in addition to the filter file
+    #[serde(skip_serializing_if = "PlaylistFilter::is_default")]
+    pub filter: PlaylistFilter,
+    /// Users tracked in addition to the ones in the users file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<TrackedUser>,
+    /// Playlists tracked in addition to the ones in the playlists file
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub playlists: Vec<TrackedPlaylist>,
+    /// Where the events of every run are sent
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<Sink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlists: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fetch {
+    /// Number of worker threads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    /// Fetch the items of every playlist on every run to record availability changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check_availability: Option<bool>,
+    /// Field selector for the playlist endpoint, empty for the full objects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlist_fields: Option<String>,
+    /// Field selector for the playlist items endpoint, empty for the full objects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item_fields: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Watch {
+    /// Interval of `spt watch` for users and playlists without their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+}
+
+/// Templates of the progress bars, see the indicatif documentation
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Progress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub main: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lower: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chars: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    /// `tty`, `plain` or `json`, detected from the terminal if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<OutputMode>,
+}
+
+impl Progress {
+    pub fn main(&self) -> &str {
+        self.main.as_deref().unwrap_or(DEFAULT_MAIN_STYLE)
+    }
+
+    pub fn lower(&self) -> &str {
+        self.lower.as_deref().unwrap_or(DEFAULT_LOWER_STYLE)
+    }
+
+    pub fn chars(&self) -> &str {
+        self.chars.as_deref().unwrap_or(DEFAULT_PROGRESS_CHARS)
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, SPTError> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            SPTError::Config(format!("Failed to read {}: {}", path.display(), err))
+        })?;
+        Config::parse(&content)
+            .map_err(|err| SPTError::Config(format!("Invalid config {}: {}", path.display(), err)))
+    }
+
+    /// Reads the config file, a missing file is an empty config unless it is `required`
+    pub fn load(path: &Path, required: bool) -> Result<Config, SPTError> {
+        match required || path.exists() {
+            true => Config::from_file(path),
+            false => Ok(Config::default()),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    pub fn to_toml(&self) -> Result<String, SPTError> {
+        toml::to_string_pretty(self).map_err(|err| SPTError::Config(err.to_string()))
+    }
+
+    /// Checks the settings and returns the problems found, credentials missing in the config
+    /// can come from the variables in `env`. The files the config points to aren't read.
+    pub fn validate(&self, env: &EnvScope) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |name: &str, result: Result<(), String>| {
+            if let Err(why) = result {
+                problems.push(format!("{}: {}", name, why));
+            }
+        };
+        let market = |market: &Option<String>| match market {
+            Some(market) => types::parse_market(market)
+                .map(|_| ())
+                .map_err(|why| format!("Invalid market {}: {}", market, why)),
+            None => Ok(()),
+        };
+        let interval = |interval: &Option<String>| match interval {
+            Some(interval) => schedule::parse_interval(interval).map(|_| ()),
+            None => Ok(()),
+        };
+
+        if let Some(credentials) = &self.credentials {
+            // Settings missing in the file can still come from the environment
+            let credentials = credentials.clone().merge(OAuthConfig::from_env(env));
+            check(
+                "credentials",
+                credentials.validate().map_err(|e| e.to_string()),
+            );
+        }
+        if self.fetch.jobs == Some(0) {
+            check("fetch.jobs", Err("At least one job is needed".to_string()));
+        }
+        check("fetch.market", market(&self.fetch.market));
+        check("watch.interval", interval(&self.watch.interval));
+        for (name, template) in [
+            ("progress.main", self.progress.main()),
+            ("progress.lower", self.progress.lower()),
+        ] {
+            check(
+                name,
+                indicatif::ProgressStyle::with_template(template)
+                    .map(|_| ())
+                    .map_err(|why| why.to_string()),
+            );
+        }
+        if self.progress.chars().chars().count() < 2 {
+            check(
+                "progress.chars",
+                Err("At least two characters are needed".to_string()),
+            );
+        }
+        check(
+            "filter",
+            self.filter.compile().map(|_| ()).map_err(|e| e.to_string()),
+        );
+
+        let mut seen = HashSet::new();
+        for user in self.users.iter() {
+            let name = format!("users.{}", user.name_or_id());
+            check(
+                &name,
+                model::UserId::from_id_or_uri(&user.id)
+                    .map(|_| ())
+                    .map_err(|why| format!("Invalid user id {}: {}", user.id, why)),
+            );
+            if !seen.insert(&user.id) {
+                check(&name, Err("Listed more than once".to_string()));
+            }
+            check(&name, market(&user.market));
+            check(&name, interval(&user.interval));
+            check(
+                &name,
+                user.filter.compile().map(|_| ()).map_err(|e| e.to_string()),
+            );
+        }
+        for playlist in self.playlists.iter() {
+            let name = format!("playlists.{}", playlist.name_or_id());
+            match query::playlist_uri(&playlist.id) {
+                Some(id) if id == playlist.id => (),
+                Some(id) => check(&name, Err(format!("Use the URI {} as id", id))),
+                None => check(&name, Err(format!("Invalid playlist id {}", playlist.id))),
+            }
+            if !seen.insert(&playlist.id) {
+                check(&name, Err("Listed more than once".to_string()));
+            }
+            check(&name, market(&playlist.market));
+            check(&name, interval(&playlist.interval));
+        }
+        for (idx, sink) in self.notify.iter().enumerate() {
+            check(&format!("notify.{}", idx + 1), sink.validate());
+        }
+        problems
+    }
+}
@@ -13,6 +13,7 @@ pub enum PlaylistEvent {
     RemovedTracks(String, String, types::PlaylistItems),
     AddedTracks(String, String, types::PlaylistItems),
     DeletedPlaylist(String),
+    TrackAvailabilityChanged(String, String, types::PlaylistItems),
 }
 impl Event for PlaylistEvent {
     fn event_type_version(&self) -> &str {
@@ -26,6 +27,32 @@ impl Event for PlaylistEvent {
             PlaylistEvent::AddedTracks(id, _, _) => id.clone(),
             PlaylistEvent::RemovedTracks(id, _, _) => id.clone(),
             PlaylistEvent::DeletedPlaylist(id) => id.clone(),
+            PlaylistEvent::TrackAvailabilityChanged(id, _, _) => id.clone(),
+        }
+    }
+}
+impl PlaylistEvent {
+    /// Names of all event variants
+    pub const NAMES: [&'static str; 7] = [
+        "CreatedPlaylist",
+        "UpdatedDesciption",
+        "UpdatedName",
+        "RemovedTracks",
+        "AddedTracks",
+        "DeletedPlaylist",
+        "TrackAvailabilityChanged",
+    ];
+
+    /// Name of the event variant, as it is stored
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaylistEvent::CreatedPlaylist(..) => "CreatedPlaylist",
+            PlaylistEvent::UpdatedDesciption(..) => "UpdatedDesciption",
+            PlaylistEvent::UpdatedName(..) => "UpdatedName",
+            PlaylistEvent::RemovedTracks(..) => "RemovedTracks",
+            PlaylistEvent::AddedTracks(..) => "AddedTracks",
+            PlaylistEvent::DeletedPlaylist(..) => "DeletedPlaylist",
+            PlaylistEvent::TrackAvailabilityChanged(..) => "TrackAvailabilityChanged",
         }
     }
 }
@@ -38,7 +65,7 @@ impl From<UniqueEvent> for PlaylistEvent {
 }
 impl From<UniqueEvent> for std::result::Result<PlaylistEvent, serde_json::Error> {
     fn from(evt: UniqueEvent) -> Self {
-        Ok(serde_json::from_value(evt.data)?)
+        serde_json::from_value(evt.data)
     }
 }
 
@@ -51,9 +78,10 @@ pub enum PlaylistCommand {
     AddTracks(String, String, types::PlaylistItems),
     RemoveTracks(String, String, types::PlaylistItems),
     DeletePlaylist(String),
+    UpdateTrackAvailability(String, String, types::PlaylistItems),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct PlaylistData {
     pub data: types::Playlist,
     pub generation: u64,
@@ -66,6 +94,11 @@ impl PlaylistData {
         }
     }
 }
+impl Default for PlaylistData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl AggregateState for PlaylistData {
     fn generation(&self) -> u64 {
         self.generation
@@ -78,7 +111,7 @@ impl Aggregate for PlaylistAggregate {
     type Command = PlaylistCommand;
 
     fn apply_event(state: Self::State, evt: &Self::Event) -> Result<Self::State> {
-        let state = match &*evt {
+        let state = match evt {
             PlaylistEvent::CreatedPlaylist(_id, playlist) => PlaylistData {
                 data: playlist.to_owned(),
                 generation: state.generation + 1,
@@ -149,6 +182,32 @@ impl Aggregate for PlaylistAggregate {
                     generation: state.generation + 1,
                 }
             }
+            PlaylistEvent::TrackAvailabilityChanged(_id, snapshot, tracks) => {
+                // Replace the stored items with their updated versions
+                let mut ntracks = state.data.tracks.clone();
+                for track in tracks.iter() {
+                    let key = track.key();
+                    for item in ntracks.0.iter_mut() {
+                        if item.key() == key {
+                            *item = track.clone();
+                        }
+                    }
+                }
+                PlaylistData {
+                    data: types::Playlist {
+                        collaborative: state.data.collaborative,
+                        followers: state.data.followers,
+                        public: state.data.public,
+                        description: state.data.description.clone(),
+                        id: state.data.id.clone(),
+                        name: state.data.name.clone(),
+                        owner: state.data.owner.clone(),
+                        tracks: ntracks,
+                        snapshot_id: snapshot.clone(),
+                    },
+                    generation: state.generation + 1,
+                }
+            }
             PlaylistEvent::DeletedPlaylist(_) => todo!(),
         };
         Ok(state)
@@ -162,7 +221,8 @@ impl Aggregate for PlaylistAggregate {
         | PlaylistCommand::DeletePlaylist(id)
         | PlaylistCommand::RemoveTracks(id, _, _)
         | PlaylistCommand::UpdateDesciption(id, _)
-        | PlaylistCommand::UpdateName(id, _) = cmd
+        | PlaylistCommand::UpdateName(id, _)
+        | PlaylistCommand::UpdateTrackAvailability(id, _, _) = cmd
         {
             if id.clone() != state.data.id {
                 return Err(Error {
@@ -207,6 +267,13 @@ impl Aggregate for PlaylistAggregate {
             PlaylistCommand::DeletePlaylist(id) => {
                 vec![PlaylistEvent::DeletedPlaylist(id.to_owned())]
             }
+            PlaylistCommand::UpdateTrackAvailability(id, snapshot_id, tracks) => {
+                vec![PlaylistEvent::TrackAvailabilityChanged(
+                    id.to_owned(),
+                    snapshot_id.to_owned(),
+                    tracks.to_owned(),
+                )]
+            }
         };
         Ok(evts)
     }
@@ -39,6 +39,10 @@ impl JSONEventStore {
         guard.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn from_file<P: AsRef<Path> + ?Sized + std::convert::AsRef<std::ffi::OsStr>>(
         path: &P,
     ) -> std::result::Result<JSONEventStore, crate::types::SPTError> {
@@ -66,12 +70,18 @@ impl JSONEventStore {
         let mut file = io::BufWriter::new(file);
         for event in events {
             let event = serde_json::to_string(&event)?;
-            write!(file, "{}\n", event)?;
+            writeln!(file, "{}", event)?;
         }
         Ok(())
     }
 }
 
+impl Default for JSONEventStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EventStore for JSONEventStore {
     /// Appends an event to the in-memory store
     fn append(&self, evt: impl Event, _stream: &str) -> Result<UniqueEvent> {
@@ -95,6 +105,33 @@ impl JSONEventStore {
         matches
     }
 
+    /// Returns the stored events of a stream, including their metadata
+    pub fn get_events(&self, id: String) -> Vec<UniqueEvent> {
+        let guard = self.evts.lock().unwrap();
+        guard
+            .iter()
+            .filter(|evt| evt.origin_id == id)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the ids of all streams in the order they first appeared in
+    pub fn origin_ids(&self) -> Vec<String> {
+        let guard = self.evts.lock().unwrap();
+        let mut seen = std::collections::HashSet::new();
+        guard
+            .iter()
+            .filter(|evt| seen.insert(evt.origin_id.as_str()))
+            .map(|evt| evt.origin_id.clone())
+            .collect()
+    }
+
+    /// Events appended after the first `index` events, e.g. the ones recorded by a run
+    pub fn events_since(&self, index: usize) -> Vec<UniqueEvent> {
+        let guard = self.evts.lock().unwrap();
+        guard.iter().skip(index).cloned().collect()
+    }
+
     pub fn get_from<E: Event + std::convert::From<UniqueEvent>>(
         &self,
         id: String,
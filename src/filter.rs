@@ -0,0 +1,119 @@
+//! Playlist Filters
+//!
+//! Include and exclude rules that decide which playlists are tracked. The global rules of a
+//! profile are stored in `data/filter.json`, every user can have additional rules in its
+//! `filter` entry. A playlist has to pass both.
+
+use crate::query;
+use crate::types::SPTError;
+use regex::Regex;
+use rspotify::model;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Rules as they are stored, see [`PlaylistFilter::compile`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct PlaylistFilter {
+    /// Only track playlists whose name matches one of these regular expressions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_names: Vec<String>,
+    /// Skip playlists whose name matches one of these regular expressions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_names: Vec<String>,
+    /// Only track these playlists (ids, URIs or links)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_ids: Vec<String>,
+    /// Skip these playlists (ids, URIs or links)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_ids: Vec<String>,
+    /// Skip playlists with fewer tracks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tracks: Option<u32>,
+    /// Skip playlists that aren't public
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public_only: bool,
+}
+
+impl PlaylistFilter {
+    pub fn is_default(&self) -> bool {
+        *self == PlaylistFilter::default()
+    }
+
+    /// Checks the rules and prepares them for matching
+    pub fn compile(&self) -> Result<Matcher, SPTError> {
+        let regexes = |patterns: &[String]| -> Result<Vec<Regex>, SPTError> {
+            patterns
+                .iter()
+                .map(|pattern| Ok(Regex::new(pattern)?))
+                .collect()
+        };
+        let ids = |ids: &[String]| -> Result<HashSet<String>, SPTError> {
+            ids.iter()
+                .map(|id| {
+                    query::playlist_uri(id)
+                        .ok_or_else(|| SPTError::Lookup(format!("Invalid playlist id {}", id)))
+                })
+                .collect()
+        };
+        Ok(Matcher {
+            include_names: regexes(&self.include_names)?,
+            exclude_names: regexes(&self.exclude_names)?,
+            include_ids: ids(&self.include_ids)?,
+            exclude_ids: ids(&self.exclude_ids)?,
+            min_tracks: self.min_tracks,
+            public_only: self.public_only,
+        })
+    }
+}
+
+/// Loads the global rules, a missing file has none
+pub fn load(path: &Path) -> Result<PlaylistFilter, SPTError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(PlaylistFilter::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Compiled [`PlaylistFilter`]
+#[derive(Debug, Clone, Default)]
+pub struct Matcher {
+    include_names: Vec<Regex>,
+    exclude_names: Vec<Regex>,
+    include_ids: HashSet<String>,
+    exclude_ids: HashSet<String>,
+    min_tracks: Option<u32>,
+    public_only: bool,
+}
+
+impl Matcher {
+    /// Returns why the playlist is filtered out, `None` if it is tracked
+    pub fn rejects(&self, playlist: &model::SimplifiedPlaylist) -> Option<String> {
+        let id = playlist.id.to_string();
+        let name = &playlist.name;
+        if !self.include_ids.is_empty() && !self.include_ids.contains(&id) {
+            return Some("not in the included ids".to_string());
+        }
+        if self.exclude_ids.contains(&id) {
+            return Some("excluded by id".to_string());
+        }
+        if !self.include_names.is_empty() && !self.include_names.iter().any(|re| re.is_match(name))
+        {
+            return Some("name doesn't match the included names".to_string());
+        }
+        if let Some(re) = self.exclude_names.iter().find(|re| re.is_match(name)) {
+            return Some(format!("name matches {}", re));
+        }
+        if let Some(min) = self.min_tracks.filter(|min| playlist.tracks.total < *min) {
+            return Some(format!(
+                "{} tracks, fewer than {}",
+                playlist.tracks.total, min
+            ));
+        }
+        if self.public_only && playlist.public != Some(true) {
+            return Some("not public".to_string());
+        }
+        None
+    }
+}
@@ -1,73 +1,212 @@
+pub mod callback;
+pub mod changes;
+pub mod cli;
+pub mod config;
 pub mod eventsourcing;
+pub mod filter;
 pub mod login;
+pub mod notify;
+pub mod pool;
+pub mod profile;
+pub mod query;
+pub mod report;
+pub mod schedule;
+pub mod token;
 pub mod types;
 
 use crate::eventsourcing::domain;
 use crate::eventsourcing::eventstore::JSONEventStore;
 use crate::eventsourcing::prelude::*;
+use chrono::{DateTime, Utc};
 use rspotify::model;
-use rspotify::AuthCodeSpotify;
-use std::collections::HashSet;
-use std::env;
+use rspotify::prelude::BaseClient;
+use std::collections::HashMap;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
-#[derive(Debug, Clone)]
-pub enum Commands {
-    DEFAULT,
-    SINGLE,
-    AddUser(Vec<String>),
-}
-impl Commands {
-    pub fn build() -> Result<Commands, &'static str> {
-        let args: Vec<String> = env::args().collect();
-        let len = args.len();
-        if len <= 1 {
-            Ok(Commands::DEFAULT)
-        } else {
-            match (len, args[1].as_str()) {
-                (2, "-s") => Ok(Commands::SINGLE),
-                (4, "-n") => Ok(Commands::AddUser(args[2..args.len()].to_vec())),
-                _ => Err("USAGE: spt.exe to update data\n       \
-                                 spt.exe -n {{name}} {{id}} to add a new name\n       \
-                                 spt.exe -s to update data for only the first user"),
-            }
-        }
-    }
-}
-
 pub fn load_users<P: AsRef<Path> + ?Sized + std::convert::AsRef<std::ffi::OsStr>>(
     path: &P,
-) -> Result<Vec<types::User>, std::io::Error> {
+) -> Result<Vec<types::TrackedUser>, std::io::Error> {
+    load_lines(path.as_ref())
+}
+
+/// Reads a file with one JSON object per line, blank lines are skipped
+fn load_lines<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>, std::io::Error> {
     let file = File::open(path)?;
     let lines = BufReader::new(file).lines();
-    let mut users = Vec::<types::User>::new();
+    let mut entries = Vec::new();
 
     for line in lines {
         let line = line?;
-        let user: types::User = serde_json::from_str(&line)?;
-        users.push(user);
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
     }
 
-    Ok(users)
+    Ok(entries)
 }
 
+/// Like [`load_lines`], a missing file has no entries
+fn load_lines_or_empty<T: serde::de::DeserializeOwned>(
+    path: &Path,
+) -> Result<Vec<T>, std::io::Error> {
+    match load_lines(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        entries => entries,
+    }
+}
+
+/// Writes one JSON object per line, see [`write_atomic`]
+fn save_lines<T: serde::Serialize>(path: &Path, entries: &[T]) -> Result<(), std::io::Error> {
+    let mut content = String::new();
+    for entry in entries {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    write_atomic(path, content.as_bytes())
+}
+
+/// Adds a user to the saved ones, users that are already tracked are rejected
 pub fn add_users<P: AsRef<Path> + ?Sized + std::convert::AsRef<std::ffi::OsStr>>(
     path: &P,
-    user: types::User,
+    user: types::TrackedUser,
 ) -> Result<(), std::io::Error> {
+    let mut users: Vec<types::TrackedUser> = load_lines_or_empty(path.as_ref())?;
+    if let Some(known) = users.iter().find(|known| known.id == user.id) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} ( {} ) is already tracked", known.name_or_id(), known.id),
+        ));
+    }
+    users.push(user);
+    save_users(path, &users)
+}
+
+/// Replaces the saved users with `users`. The file is written atomically, so an interrupted
+/// write never loses users.
+pub fn save_users<P: AsRef<Path> + ?Sized>(
+    path: &P,
+    users: &[types::TrackedUser],
+) -> Result<(), std::io::Error> {
+    save_lines(path.as_ref(), users)
+}
+
+/// Writes to a temporary file next to `path` and renames it over `path`
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
     use std::io::Write;
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(path)?;
-    let user = serde_json::to_string(&user)?;
-    write!(file, "{}\n", user)?;
-    Ok(())
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp~");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Removes users that are listed more than once, the first entry is kept.
+/// Returns the removed duplicates.
+pub fn dedup_users(users: &mut Vec<types::TrackedUser>) -> Vec<types::TrackedUser> {
+    let mut seen = std::collections::HashSet::new();
+    let mut duplicates = Vec::new();
+    users.retain(|user| {
+        let first = seen.insert(user.id.clone());
+        if !first {
+            duplicates.push(user.clone());
+        }
+        first
+    });
+    duplicates
+}
+
+/// Checks that the user exists and fills in the display name if none is set.
+/// Returns `true` if the user was changed.
+pub fn validate_user<C: BaseClient>(
+    client: &C,
+    user: &mut types::TrackedUser,
+) -> Result<bool, types::SPTError> {
+    let id = model::UserId::from_id_or_uri(&user.id)
+        .map_err(|why| types::SPTError::Lookup(format!("Invalid user id {}: {}", user.id, why)))?;
+    let public = client.user(id)?;
+    let mut changed = false;
+    if public.id.to_string() != user.id {
+        // Normalize bare ids to the URI form used everywhere else
+        user.id = public.id.to_string();
+        changed = true;
+    }
+    if user.display_name.is_none() && public.display_name.is_some() {
+        user.display_name = public.display_name;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Finds a user by id, URI or display name
+pub fn find_user<'a>(
+    users: &'a [types::TrackedUser],
+    query: &str,
+) -> Option<&'a types::TrackedUser> {
+    let uri = model::UserId::from_id_or_uri(query)
+        .map(|id| id.to_string())
+        .ok();
+    users
+        .iter()
+        .find(|user| Some(&user.id) == uri.as_ref() || user.id == query)
+        .or_else(|| {
+            users
+                .iter()
+                .find(|user| user.display_name.as_deref() == Some(query))
+        })
+}
+
+/// Loads the playlists tracked on their own, a missing file has none
+pub fn load_playlists<P: AsRef<Path> + ?Sized>(
+    path: &P,
+) -> Result<Vec<types::TrackedPlaylist>, std::io::Error> {
+    load_lines_or_empty(path.as_ref())
+}
+
+/// Adds a playlist to the saved ones, playlists that are already tracked are rejected
+pub fn add_playlists<P: AsRef<Path> + ?Sized>(
+    path: &P,
+    playlist: types::TrackedPlaylist,
+) -> Result<(), std::io::Error> {
+    let mut playlists = load_playlists(path)?;
+    if let Some(known) = playlists.iter().find(|known| known.id == playlist.id) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} ( {} ) is already tracked", known.name_or_id(), known.id),
+        ));
+    }
+    playlists.push(playlist);
+    save_playlists(path, &playlists)
+}
+
+/// Replaces the saved playlists with `playlists`, written atomically like the users
+pub fn save_playlists<P: AsRef<Path> + ?Sized>(
+    path: &P,
+    playlists: &[types::TrackedPlaylist],
+) -> Result<(), std::io::Error> {
+    save_lines(path.as_ref(), playlists)
+}
+
+/// Finds a tracked playlist by id, URI, open.spotify.com link or name
+pub fn find_tracked_playlist<'a>(
+    playlists: &'a [types::TrackedPlaylist],
+    query: &str,
+) -> Option<&'a types::TrackedPlaylist> {
+    let uri = query::playlist_uri(query);
+    playlists
+        .iter()
+        .find(|playlist| Some(&playlist.id) == uri.as_ref())
+        .or_else(|| {
+            playlists
+                .iter()
+                .find(|playlist| playlist.name.as_deref() == Some(query))
+        })
 }
 
 /// Rebuild playlist state from events
@@ -81,106 +220,173 @@ pub fn build_local(
     Ok(state)
 }
 
-/// compare local and new version and return events if changes occured
-pub fn compare(
+/// Rebuild playlist state as it was at `at`, only the events recorded until then are applied.
+/// A playlist that wasn't tracked yet has generation 0.
+pub fn build_at(
+    origin_id: &str,
+    pl_store: &JSONEventStore,
+    at: DateTime<Utc>,
+) -> eventsourcing::Result<domain::PlaylistData> {
+    let events: Vec<domain::PlaylistEvent> =
+        pl_store.get_range(origin_id.to_string(), DateTime::<Utc>::MIN_UTC, at);
+    domain::PlaylistAggregate::apply_all(domain::PlaylistData::new(), &events)
+}
+
+/// Lists the currently unavailable items of every playlist in the store
+pub fn unavailable_tracks(
+    pl_store: &JSONEventStore,
+) -> eventsourcing::Result<Vec<(domain::PlaylistData, types::PlaylistItems)>> {
+    let mut report = Vec::new();
+    for origin_id in pl_store.origin_ids() {
+        let state = build_local(&origin_id, pl_store)?;
+        let unavailable: types::PlaylistItems = state
+            .data
+            .tracks
+            .iter()
+            .filter(|item| item.is_unavailable())
+            .cloned()
+            .collect();
+        if !unavailable.is_empty() {
+            report.push((state, unavailable));
+        }
+    }
+    Ok(report)
+}
+
+/// compare local and new version and return events if changes occured. Every change is also
+/// passed to the `reporter`, `username` labels the changes.
+pub fn compare<C: BaseClient>(
     username: &str,
-    multi: &indicatif::MultiProgress,
-    client: &AuthCodeSpotify,
+    reporter: &dyn changes::ChangeReporter,
+    client: &C,
     state: &domain::PlaylistData,
     playlist: &model::SimplifiedPlaylist,
-    fields: Option<&str>,
-    market: Option<rspotify::model::Market>,
+    options: &types::FetchOptions,
 ) -> Result<Vec<domain::PlaylistEvent>, types::SPTError> {
     let mut plevents: Vec<domain::PlaylistEvent> = Vec::new();
 
     if state.generation == 0 {
-        multi
-            .println(format!(
-                "[{}] Created {} ( {} )",
-                username, playlist.name, playlist.id
-            ))
-            .unwrap();
-        let playlist = types::Playlist::from_id(&client, playlist.id.clone(), fields, market)?;
+        let playlist = types::Playlist::from_id(client, playlist.id.clone(), options)?;
         let cmd = domain::PlaylistCommand::CreatePlaylist(playlist.id.clone(), playlist.clone());
-        let evts = domain::PlaylistAggregate::handle_command(&state, &cmd)?;
+        let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
         plevents.extend(evts);
+        reporter.created(&changes::Subject {
+            label: username,
+            id: &playlist.id.to_string(),
+            name: &playlist.name,
+        });
     } else {
         // Saved my ass already, good assert
         assert!(state.data.id == playlist.id.to_string());
+        let subject = changes::Subject {
+            label: username,
+            id: &state.data.id,
+            name: &state.data.name,
+        };
 
         // UpdateName Event
         if state.data.name != playlist.name {
-            multi
-                .println(format!(
-                    "[{}] Updated name for {} ( {} )",
-                    username, state.data.name, state.data.id
-                ))
-                .unwrap();
             let cmd =
                 domain::PlaylistCommand::UpdateName(playlist.id.to_string(), playlist.name.clone());
-            let evts = domain::PlaylistAggregate::handle_command(&state, &cmd)?;
+            let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
             plevents.extend(evts);
+            reporter.renamed(&subject, &state.data.name, &playlist.name);
         }
 
-        if state.data.snapshot_id != playlist.snapshot_id {
-            let playlist = types::Playlist::from_id(client, playlist.id.clone(), fields, market)?;
+        // Availability changes don't touch the snapshot id, so catching them means fetching
+        // the items on every run. Spotify only reports availability with a market.
+        let check_availability = options.check_availability && options.market.is_some();
+        if state.data.snapshot_id != playlist.snapshot_id || check_availability {
+            let playlist = types::Playlist::from_id(client, playlist.id.clone(), options)?;
 
             // UpdateDescription Event
             if state.data.description != playlist.description {
-                multi
-                    .println(format!(
-                        "[{}] Updated description for {} ( {} )",
-                        username, state.data.name, state.data.id
-                    ))
-                    .unwrap();
                 let cmd = domain::PlaylistCommand::UpdateDesciption(
                     playlist.id.to_string(),
                     playlist.description.clone(),
                 );
-                let evts = domain::PlaylistAggregate::handle_command(&state, &cmd)?;
+                let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
                 plevents.extend(evts);
+                reporter.description_changed(
+                    &subject,
+                    state.data.description.as_deref(),
+                    playlist.description.as_deref(),
+                );
             }
 
             if state.data.tracks != playlist.tracks {
-                let plhash: HashSet<types::PlaylistItem> =
-                    playlist.tracks.iter().cloned().collect();
-                let localphash: HashSet<types::PlaylistItem> =
-                    state.data.tracks.iter().cloned().collect();
+                // Match items by their identity so that metadata updates and market relinking
+                // aren't churn
+                let plhash: HashMap<types::ItemKey, &types::PlaylistItem> = playlist
+                    .tracks
+                    .iter()
+                    .map(|item| (item.key(), item))
+                    .collect();
+                let localphash: HashMap<types::ItemKey, &types::PlaylistItem> = state
+                    .data
+                    .tracks
+                    .iter()
+                    .map(|item| (item.key(), item))
+                    .collect();
 
                 // AddTracks Event
-                let addedtracks: HashSet<_> = plhash.difference(&localphash).collect();
+                let addedtracks: types::PlaylistItems = playlist
+                    .tracks
+                    .iter()
+                    .filter(|item| !localphash.contains_key(&item.key()))
+                    .cloned()
+                    .collect();
                 if !addedtracks.is_empty() {
-                    multi
-                        .println(format!(
-                            "[{}] Added tracks to {} ( {} ) ",
-                            username, state.data.name, state.data.id
-                        ))
-                        .unwrap();
                     let cmd = domain::PlaylistCommand::AddTracks(
                         playlist.id.clone(),
                         playlist.snapshot_id.clone(),
-                        addedtracks.into_iter().cloned().collect(),
+                        addedtracks.clone(),
                     );
-                    let evts = domain::PlaylistAggregate::handle_command(&state, &cmd)?;
+                    let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
                     plevents.extend(evts);
+                    reporter.tracks_added(&subject, &addedtracks);
                 }
 
                 // RemovedTracks Event
-                let removedtracks: HashSet<_> = localphash.difference(&plhash).collect();
+                let removedtracks: types::PlaylistItems = state
+                    .data
+                    .tracks
+                    .iter()
+                    .filter(|item| !plhash.contains_key(&item.key()))
+                    .cloned()
+                    .collect();
                 if !removedtracks.is_empty() {
-                    multi
-                        .println(format!(
-                            "[{}] Removed tracks from {} ( {} ) ",
-                            username, state.data.name, state.data.id
-                        ))
-                        .unwrap();
                     let cmd = domain::PlaylistCommand::RemoveTracks(
                         playlist.id.clone(),
                         playlist.snapshot_id.clone(),
-                        removedtracks.into_iter().cloned().collect(),
+                        removedtracks.clone(),
+                    );
+                    let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
+                    plevents.extend(evts);
+                    reporter.tracks_removed(&subject, &removedtracks);
+                }
+
+                // TrackAvailabilityChanged Event
+                // Unknown availability counts as playable, so setting a market isn't a change
+                let changedtracks: types::PlaylistItems = playlist
+                    .tracks
+                    .iter()
+                    .filter(|item| {
+                        localphash
+                            .get(&item.key())
+                            .is_some_and(|local| !local.same_availability(item))
+                    })
+                    .cloned()
+                    .collect();
+                if !changedtracks.is_empty() {
+                    let cmd = domain::PlaylistCommand::UpdateTrackAvailability(
+                        playlist.id.clone(),
+                        playlist.snapshot_id.clone(),
+                        changedtracks.clone(),
                     );
-                    let evts = domain::PlaylistAggregate::handle_command(&state, &cmd)?;
+                    let evts = domain::PlaylistAggregate::handle_command(state, &cmd)?;
                     plevents.extend(evts);
+                    reporter.availability_changed(&subject, &changedtracks);
                 }
             }
         }
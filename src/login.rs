@@ -1,21 +1,52 @@
-use rspotify::{prelude::*, scopes, AuthCodeSpotify, Credentials, OAuth};
+use crate::callback;
+use crate::token;
+use rspotify::{prelude::*, AuthCodeSpotify, ClientCredsSpotify, Config, Credentials, OAuth};
+use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::HashSet;
+use std::env;
 use std::error;
 use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-const TOKEN_PATH: &str = "token.tmp";
-const CLIENT_ID: &str = "7d5c06725d314f0b975c88c7ca23b4d8";
-const CLIENT_SECRET: &str = "832bd9a9d9144c62a3b1c3e9c26906ff";
-const REDIRECT_URI: &str = "http://localhost:65432";
-const SCOPES: &str = "playlist-modify-public, user-read-currently-playing";
+const CREDENTIALS_PATH: &str = "credentials.json";
+const DEFAULT_REDIRECT_URI: &str = "http://localhost:65432";
+const DEFAULT_SCOPES: &str = "playlist-modify-public, user-read-currently-playing";
+
+const CREDENTIALS_VAR: &str = "SPT_CREDENTIALS";
+const CLIENT_ID_VAR: &str = "SPT_CLIENT_ID";
+const CLIENT_SECRET_VAR: &str = "SPT_CLIENT_SECRET";
+const REDIRECT_URI_VAR: &str = "SPT_REDIRECT_URI";
+const SCOPES_VAR: &str = "SPT_SCOPES";
+const FLOW_VAR: &str = "SPT_FLOW";
+const REFRESH_TOKEN_VAR: &str = "SPT_REFRESH_TOKEN";
+const REFRESH_TOKEN_FILE_VAR: &str = "SPT_REFRESH_TOKEN_FILE";
+const TOKEN_FILE_VAR: &str = "SPT_TOKEN_FILE";
+const TOKEN_PASSPHRASE_VAR: &str = "SPT_TOKEN_PASSPHRASE";
+const HEADLESS_VAR: &str = "SPT_HEADLESS";
+const LOGIN_TIMEOUT_VAR: &str = "SPT_LOGIN_TIMEOUT";
+const DEFAULT_LOGIN_TIMEOUT: u64 = 300;
+/// How long before it expires the token is refreshed in the background
+const REFRESH_MARGIN_SECS: i64 = 5 * 60;
+/// Wait before retrying a failed refresh
+const REFRESH_RETRY: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub enum AuthenticationError {
     IOError(std::io::Error),
     ParseError(serde_json::Error),
     ClientError(rspotify::ClientError),
+    ConfigError(String),
+    /// The saved token can't be used and logging in again needs a user
+    ReauthenticationRequired(String),
+    /// The login redirect was invalid, denied or never arrived
+    CallbackError(String),
+    /// The saved token can't be decrypted or encrypted
+    TokenError(String),
 }
 
 impl fmt::Display for AuthenticationError {
@@ -24,6 +55,10 @@ impl fmt::Display for AuthenticationError {
             AuthenticationError::IOError(err) => write!(f, "{}", err),
             AuthenticationError::ParseError(err) => write!(f, "{}", err),
             AuthenticationError::ClientError(err) => write!(f, "{}", err),
+            AuthenticationError::ConfigError(err) => write!(f, "{}", err),
+            AuthenticationError::ReauthenticationRequired(err) => write!(f, "{}", err),
+            AuthenticationError::CallbackError(err) => write!(f, "{}", err),
+            AuthenticationError::TokenError(err) => write!(f, "{}", err),
         }
     }
 }
@@ -48,50 +83,609 @@ impl From<rspotify::ClientError> for AuthenticationError {
 
 impl error::Error for AuthenticationError {}
 
-fn load_token(path: &Path) -> Result<rspotify::Token, AuthenticationError> {
-    let token = fs::read_to_string(&path)?;
-    let token = serde_json::from_str(&token)?;
-    Ok(token)
+/// What to do if the saved token can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Let the user log in through the browser
+    Interactive,
+    /// Fail with [`AuthenticationError::ReauthenticationRequired`] instead of waiting for a user
+    Headless,
 }
 
-fn save_token(token: rspotify::Token, path: &Path) -> Result<(), AuthenticationError> {
-    let file = fs::File::create(&path)?;
-    Ok(serde_json::to_writer(file, &token)?)
+/// Where the credential variables (`SPT_CLIENT_ID`, `SPT_REFRESH_TOKEN`, ...) are read from.
+/// A named profile first looks for its own `SPT_<PROFILE>_CLIENT_ID` etc. The plain variables
+/// only apply while a single profile runs, otherwise every profile of `--all-profiles` would
+/// log in as the same account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvScope {
+    /// Upper case profile name with `-` replaced by `_`
+    prefix: Option<String>,
+    /// Whether the plain variables apply
+    shared: bool,
 }
 
-fn auth_with_prev_token(spotify: &AuthCodeSpotify) -> Result<(), AuthenticationError> {
-    let prev_token = load_token(Path::new(TOKEN_PATH))?;
-    *spotify.token.lock().unwrap() = Some(prev_token);
-    Ok(spotify.refresh_token()?)
+impl EnvScope {
+    pub fn new(profile: Option<&str>, shared: bool) -> EnvScope {
+        EnvScope {
+            prefix: profile.map(|name| name.to_uppercase().replace('-', "_")),
+            shared,
+        }
+    }
+
+    /// Name of `var` for the profile, e.g. `SPT_WORK_CLIENT_ID` for `SPT_CLIENT_ID`
+    pub fn scoped(&self, var: &str) -> Option<String> {
+        let prefix = self.prefix.as_ref()?;
+        Some(var.replacen("SPT_", &format!("SPT_{}_", prefix), 1))
+    }
+
+    /// Value of `var`, the one of the profile takes precedence
+    pub fn var(&self, var: &str) -> Option<String> {
+        self.scoped(var)
+            .and_then(|scoped| env::var(scoped).ok())
+            .or_else(|| env::var(var).ok().filter(|_| self.shared))
+    }
+}
+
+impl Default for EnvScope {
+    /// Only the plain variables, as used without profiles
+    fn default() -> Self {
+        EnvScope::new(None, true)
+    }
+}
+
+impl AuthMode {
+    /// Headless if `SPT_HEADLESS` is set or stdin isn't a terminal (e.g. in cron jobs)
+    pub fn detect() -> AuthMode {
+        use std::io::IsTerminal;
+
+        AuthMode::resolve(
+            env::var(HEADLESS_VAR).ok().as_deref(),
+            std::io::stdin().is_terminal(),
+        )
+    }
+
+    /// The mode for a `SPT_HEADLESS` value, an empty value, `0` and `false` don't force it
+    pub fn resolve(headless: Option<&str>, terminal: bool) -> AuthMode {
+        let headless = headless.is_some_and(|value| !matches!(value.trim(), "" | "0" | "false"));
+        if headless || !terminal {
+            AuthMode::Headless
+        } else {
+            AuthMode::Interactive
+        }
+    }
+}
+
+/// How the tracker authenticates with Spotify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Log in as a user, needed for private and collaborative playlists
+    AuthorizationCode,
+    /// Authenticate only the app, enough to track public playlists
+    ClientCredentials,
+}
+
+impl Flow {
+    fn parse(flow: &str) -> Option<Flow> {
+        match flow.trim() {
+            "authorization_code" => Some(Flow::AuthorizationCode),
+            "client_credentials" => Some(Flow::ClientCredentials),
+            _ => None,
+        }
+    }
+}
+
+/// An authenticated Spotify client
+#[derive(Debug, Clone)]
+pub enum Client {
+    User(AuthCodeSpotify),
+    App(ClientCredsSpotify),
+}
+
+/// OAuth settings of the Spotify app used to log in.
+///
+/// The settings are read from `credentials.json` (or the file in `SPT_CREDENTIALS`) and
+/// can be overridden field by field with the `SPT_CLIENT_ID`, `SPT_CLIENT_SECRET`,
+/// `SPT_REDIRECT_URI`, `SPT_SCOPES` and `SPT_FLOW` environment variables.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct OAuthConfig {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    /// `authorization_code` (default) or `client_credentials`
+    pub flow: Option<String>,
+}
+
+impl OAuthConfig {
+    /// Loads the settings from the credentials file and the environment and validates them
+    pub fn load() -> Result<OAuthConfig, AuthenticationError> {
+        let path = env::var(CREDENTIALS_VAR).unwrap_or_else(|_| CREDENTIALS_PATH.to_string());
+        OAuthConfig::load_from(Path::new(&path), &EnvScope::default())
+    }
+
+    /// Overrides the settings with the environment and validates them
+    pub fn with_env(self, env: &EnvScope) -> Result<OAuthConfig, AuthenticationError> {
+        let config = self.merge(OAuthConfig::from_env(env));
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like [`OAuthConfig::load`] but with the credentials file at `path`
+    pub fn load_from(path: &Path, env: &EnvScope) -> Result<OAuthConfig, AuthenticationError> {
+        let config = match OAuthConfig::from_file(path) {
+            Ok(config) => config,
+            Err(AuthenticationError::IOError(err))
+                if err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                OAuthConfig::default()
+            }
+            Err(err) => {
+                return Err(AuthenticationError::ConfigError(format!(
+                    "Failed to read {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        config.with_env(env)
+    }
+
+    pub fn from_file(path: &Path) -> Result<OAuthConfig, AuthenticationError> {
+        let config = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&config)?)
+    }
+
+    pub fn from_env(env: &EnvScope) -> OAuthConfig {
+        OAuthConfig {
+            client_id: env.var(CLIENT_ID_VAR),
+            client_secret: env.var(CLIENT_SECRET_VAR),
+            redirect_uri: env.var(REDIRECT_URI_VAR),
+            scopes: env.var(SCOPES_VAR).map(|scopes| parse_scopes(&scopes)),
+            flow: env.var(FLOW_VAR),
+        }
+    }
+
+    /// Returns the settings of `self` overridden by every setting present in `other`
+    pub fn merge(self, other: OAuthConfig) -> OAuthConfig {
+        OAuthConfig {
+            client_id: other.client_id.or(self.client_id),
+            client_secret: other.client_secret.or(self.client_secret),
+            redirect_uri: other.redirect_uri.or(self.redirect_uri),
+            scopes: other.scopes.or(self.scopes),
+            flow: other.flow.or(self.flow),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AuthenticationError> {
+        let missing = |name: &str, var: &str| {
+            AuthenticationError::ConfigError(format!(
+                "No {} configured, set it in {} or with {}",
+                name, CREDENTIALS_PATH, var
+            ))
+        };
+        match self.client_id.as_deref().map(str::trim) {
+            None | Some("") => return Err(missing("client_id", CLIENT_ID_VAR)),
+            Some(id) if !id.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                return Err(AuthenticationError::ConfigError(format!(
+                    "Invalid client_id {}",
+                    id
+                )))
+            }
+            Some(_) => (),
+        }
+        if let None | Some("") = self.client_secret.as_deref().map(str::trim) {
+            return Err(missing("client_secret", CLIENT_SECRET_VAR));
+        }
+        if let Some(flow) = &self.flow {
+            if Flow::parse(flow).is_none() {
+                return Err(AuthenticationError::ConfigError(format!(
+                    "Invalid flow {}, use authorization_code or client_credentials",
+                    flow
+                )));
+            }
+        }
+        let redirect_uri = self.redirect_uri();
+        if !redirect_uri.starts_with("http://") && !redirect_uri.starts_with("https://") {
+            return Err(AuthenticationError::ConfigError(format!(
+                "Invalid redirect_uri {}, it has to be a http(s) url",
+                redirect_uri
+            )));
+        }
+        if self.scopes().is_empty() {
+            return Err(AuthenticationError::ConfigError(
+                "At least one scope has to be configured".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds scopes to the configured ones (or the default ones if none are configured)
+    pub fn with_scopes(mut self, scopes: &[&str]) -> OAuthConfig {
+        let mut all = self
+            .scopes
+            .take()
+            .unwrap_or_else(|| parse_scopes(DEFAULT_SCOPES));
+        for scope in scopes {
+            if !all.iter().any(|known| known.trim() == *scope) {
+                all.push(scope.to_string());
+            }
+        }
+        self.scopes = Some(all);
+        self
+    }
+
+    pub fn flow(&self) -> Flow {
+        self.flow
+            .as_deref()
+            .and_then(Flow::parse)
+            .unwrap_or(Flow::AuthorizationCode)
+    }
+
+    pub fn redirect_uri(&self) -> String {
+        self.redirect_uri
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REDIRECT_URI.to_string())
+    }
+
+    pub fn scopes(&self) -> HashSet<String> {
+        match &self.scopes {
+            Some(scopes) => scopes
+                .iter()
+                .map(|scope| scope.trim().to_string())
+                .filter(|scope| !scope.is_empty())
+                .collect(),
+            None => parse_scopes(DEFAULT_SCOPES).into_iter().collect(),
+        }
+    }
+}
+
+/// Splits a comma or whitespace separated list of scopes
+fn parse_scopes(scopes: &str) -> Vec<String> {
+    scopes
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|scope| !scope.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Location of the saved token, `SPT_TOKEN_FILE` or the default one in the state directory
+pub fn token_path() -> PathBuf {
+    token_path_override().unwrap_or_else(token::default_path)
+}
+
+/// Token file set in `SPT_TOKEN_FILE`
+pub fn token_path_override() -> Option<PathBuf> {
+    env::var(TOKEN_FILE_VAR)
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// Passphrase to encrypt the saved token with, if one is set in `SPT_TOKEN_PASSPHRASE`
+pub fn token_passphrase() -> Option<String> {
+    env::var(TOKEN_PASSPHRASE_VAR)
+        .ok()
+        .filter(|passphrase| !passphrase.is_empty())
+}
+
+/// Moves a token saved by older versions in the working directory to the default token path
+fn migrate_legacy_token(path: &Path) -> Result<(), AuthenticationError> {
+    if path != token_path() {
+        return Ok(());
+    }
+    let legacy = Path::new(token::LEGACY_TOKEN_PATH);
+    if token::migrate(legacy, path, token_passphrase().as_deref())? {
+        eprintln!(
+            "Moved the saved token from {} to {}",
+            legacy.display(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Reads a refresh token from a file that either contains a saved token or just the refresh token
+fn load_refresh_token(path: &Path) -> Result<String, AuthenticationError> {
+    let content = fs::read_to_string(path)?;
+    match token::decode(&content, token_passphrase().as_deref()) {
+        Ok(token) => token.refresh_token.ok_or_else(|| {
+            AuthenticationError::ConfigError(format!(
+                "{} contains no refresh token",
+                path.display()
+            ))
+        }),
+        Err(err @ AuthenticationError::TokenError(_)) => Err(err),
+        Err(_) => Ok(content.trim().to_string()),
+    }
+}
+
+/// Finds a refresh token in `SPT_REFRESH_TOKEN`, the file in `SPT_REFRESH_TOKEN_FILE` or the
+/// saved token at `path`, in that order
+pub fn stored_refresh_token(
+    path: &Path,
+    env: &EnvScope,
+) -> Result<Option<String>, AuthenticationError> {
+    if let Some(refresh_token) = env.var(REFRESH_TOKEN_VAR) {
+        return Ok(Some(refresh_token.trim().to_string()));
+    }
+    if let Some(path) = env.var(REFRESH_TOKEN_FILE_VAR) {
+        return Ok(Some(load_refresh_token(Path::new(&path))?));
+    }
+    match token::load(path, token_passphrase().as_deref()) {
+        Ok(token) => Ok(token.refresh_token),
+        Err(AuthenticationError::IOError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn auth_with_refresh_token(
+    spotify: &AuthCodeSpotify,
+    refresh_token: String,
+) -> Result<(), AuthenticationError> {
+    let token = rspotify::Token {
+        refresh_token: Some(refresh_token),
+        ..Default::default()
+    };
+    *spotify.token.lock().unwrap() = Some(token);
+    spotify.refresh_token()?;
+    // rspotify drops the token without an error if it couldn't be refreshed
+    match &*spotify.token.lock().unwrap() {
+        Some(token) if !token.access_token.is_empty() => Ok(()),
+        _ => Err(AuthenticationError::ConfigError(
+            "Spotify didn't return an access token".to_string(),
+        )),
+    }
+}
+
+fn auth_with_prev_token(
+    spotify: &AuthCodeSpotify,
+    path: &Path,
+    env: &EnvScope,
+) -> Result<(), AuthenticationError> {
+    match stored_refresh_token(path, env)? {
+        Some(refresh_token) => auth_with_refresh_token(spotify, refresh_token)?,
+        None => {
+            return Err(AuthenticationError::ConfigError(
+                "No saved token available".to_string(),
+            ))
+        }
+    }
+    // The scopes of a refresh token are fixed, newly configured ones need a new login
+    let missing = missing_scopes(spotify);
+    if !missing.is_empty() {
+        return Err(AuthenticationError::ConfigError(format!(
+            "The saved token lacks the scopes {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Configured scopes that weren't granted to the current token
+fn missing_scopes(spotify: &AuthCodeSpotify) -> Vec<String> {
+    let token = spotify.token.lock().unwrap();
+    match &*token {
+        // Spotify doesn't always report the granted scopes
+        Some(token) if !token.scopes.is_empty() => {
+            let mut missing: Vec<String> = spotify
+                .oauth
+                .scopes
+                .difference(&token.scopes)
+                .cloned()
+                .collect();
+            missing.sort();
+            missing
+        }
+        _ => vec![],
+    }
 }
 
 fn auth_with_fresh_token(spotify: &AuthCodeSpotify) -> Result<(), AuthenticationError> {
     let url = spotify.get_authorize_url(true)?;
-    spotify.prompt_for_token(&url)?;
+
+    // Catch the redirect ourselves if it points to this machine, otherwise (or if the port is
+    // taken) fall back to pasting the redirect URL by hand
+    let listener = match callback::Listener::bind(&spotify.oauth.redirect_uri) {
+        Some(Ok(listener)) => listener,
+        Some(Err(err)) => {
+            eprintln!("Couldn't listen on {}: {}", spotify.oauth.redirect_uri, err);
+            spotify.prompt_for_token(&url)?;
+            return Ok(());
+        }
+        None => {
+            spotify.prompt_for_token(&url)?;
+            return Ok(());
+        }
+    };
+
+    match webbrowser::open(&url) {
+        Ok(_) => eprintln!("Opened {} in your browser.", url),
+        Err(_) => eprintln!("Please open this URL in your browser: {}", url),
+    }
+    let timeout = env::var(LOGIN_TIMEOUT_VAR)
+        .ok()
+        .and_then(|timeout| timeout.trim().parse().ok())
+        .unwrap_or(DEFAULT_LOGIN_TIMEOUT);
+    eprintln!(
+        "Waiting for the login redirect on {} ...",
+        spotify.oauth.redirect_uri
+    );
+    let code = listener.wait_for_code(&spotify.oauth.state, Duration::from_secs(timeout))?;
+    spotify.request_token(&code)?;
     Ok(())
 }
 
-pub fn login() -> Result<AuthCodeSpotify, AuthenticationError> {
-    let creds = Credentials::new(CLIENT_ID, CLIENT_SECRET);
+fn client(config: &OAuthConfig) -> Result<AuthCodeSpotify, AuthenticationError> {
+    config.validate()?;
+    let creds = Credentials::new(
+        config.client_id.as_deref().unwrap_or_default(),
+        config.client_secret.as_deref().unwrap_or_default(),
+    );
     let oauth = OAuth {
-        redirect_uri: REDIRECT_URI.to_string(),
-        scopes: scopes!(SCOPES),
+        redirect_uri: config.redirect_uri(),
+        scopes: config.scopes(),
         ..Default::default()
     };
-    let spotify = AuthCodeSpotify::new(creds, oauth);
-
-    if auth_with_prev_token(&spotify).is_ok() {
-        println!("Successfully authenticated with saved token!");
-    } else {
-        auth_with_fresh_token(&spotify)?;
-        println!("Successfully authenticated with fresh token!");
-    }
+    Ok(AuthCodeSpotify::new(creds, oauth))
+}
 
+fn save_client_token(
+    spotify: &AuthCodeSpotify,
+    path: &Path,
+) -> Result<Option<rspotify::Token>, AuthenticationError> {
     let token = spotify.get_token();
-    match &*token.lock().unwrap() {
-        Some(token) => save_token(token.clone(), Path::new(TOKEN_PATH))?,
-        None => println!("Couldn't save token for further use!"),
+    let token = token.lock().unwrap().clone();
+    match &token {
+        Some(token) => token::save(token, path, token_passphrase().as_deref())?,
+        None => eprintln!("Couldn't save token for further use!"),
     };
+    Ok(token)
+}
+
+pub fn login() -> Result<AuthCodeSpotify, AuthenticationError> {
+    login_with(
+        &OAuthConfig::load()?,
+        &token_path(),
+        AuthMode::detect(),
+        &EnvScope::default(),
+    )
+}
+
+/// Logs in with the token saved at `token`, the new token is saved there as well. Status
+/// messages go to stderr, stdout is left to the output of the commands.
+pub fn login_with(
+    config: &OAuthConfig,
+    token: &Path,
+    mode: AuthMode,
+    env: &EnvScope,
+) -> Result<AuthCodeSpotify, AuthenticationError> {
+    let spotify = client(config)?;
+    migrate_legacy_token(token)?;
 
-    return Ok(spotify);
+    match auth_with_prev_token(&spotify, token, env) {
+        Ok(()) => eprintln!("Successfully authenticated with saved token!"),
+        Err(err) if mode == AuthMode::Headless => {
+            return Err(AuthenticationError::ReauthenticationRequired(format!(
+                "Couldn't authenticate with the saved token: {}",
+                err
+            )))
+        }
+        Err(_) => {
+            auth_with_fresh_token(&spotify)?;
+            eprintln!("Successfully authenticated with fresh token!");
+        }
+    }
+
+    save_client_token(&spotify, token)?;
+    Ok(spotify)
+}
+
+/// Authenticates only the app with the client credentials flow. No user has to log in but
+/// only public data is available.
+pub fn login_app(config: &OAuthConfig) -> Result<ClientCredsSpotify, AuthenticationError> {
+    config.validate()?;
+    let creds = Credentials::new(
+        config.client_id.as_deref().unwrap_or_default(),
+        config.client_secret.as_deref().unwrap_or_default(),
+    );
+    // The app token can't be refreshed, a new one is requested once it expires
+    let spotify = ClientCredsSpotify::with_config(
+        creds,
+        Config {
+            token_refreshing: true,
+            ..Default::default()
+        },
+    );
+    spotify.request_token()?;
+    eprintln!("Successfully authenticated the app!");
+    Ok(spotify)
+}
+
+/// Authenticates with the flow configured for `config`
+pub fn connect(
+    config: &OAuthConfig,
+    token: &Path,
+    mode: AuthMode,
+    env: &EnvScope,
+) -> Result<Client, AuthenticationError> {
+    match config.flow() {
+        Flow::AuthorizationCode => Ok(Client::User(login_with(config, token, mode, env)?)),
+        Flow::ClientCredentials => Ok(Client::App(login_app(config)?)),
+    }
+}
+
+/// Refreshes the token of `spotify` shortly before it expires and saves it to `path`, so a
+/// long running process never uses an expired token. Clones of `spotify` share the token.
+/// The thread stops once `stop` receives a message or its sender is dropped.
+pub fn refresh_in_background(
+    spotify: AuthCodeSpotify,
+    path: PathBuf,
+    stop: mpsc::Receiver<()>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut failed = false;
+        loop {
+            let expires_at = spotify
+                .token
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|token| token.expires_at);
+            let wait = match expires_at {
+                Some(expires_at) if !failed => (expires_at
+                    - chrono::Duration::seconds(REFRESH_MARGIN_SECS)
+                    - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+                _ => REFRESH_RETRY,
+            };
+            match stop.recv_timeout(wait) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => return,
+            }
+
+            // rspotify drops the token if refreshing fails, keep the old one to try again
+            let previous = spotify.token.lock().unwrap().clone();
+            let result = spotify.refresh_token();
+            let refreshed = matches!(
+                &*spotify.token.lock().unwrap(),
+                Some(token) if !token.access_token.is_empty()
+            );
+            failed = result.is_err() || !refreshed;
+            if failed {
+                eprintln!(
+                    "Failed to refresh the token, retrying in {}s: {}",
+                    REFRESH_RETRY.as_secs(),
+                    result.err().map_or_else(
+                        || "Spotify didn't return an access token".to_string(),
+                        |err| err.to_string()
+                    )
+                );
+                *spotify.token.lock().unwrap() = previous;
+            } else if let Err(err) = save_client_token(&spotify, &path) {
+                eprintln!("Failed to save the refreshed token: {}", err);
+            }
+        }
+    })
+}
+
+/// Runs the interactive login regardless of any saved token and saves the new token.
+/// The token is returned so it can be exported for headless runs.
+pub fn authenticate(
+    config: &OAuthConfig,
+    token: &Path,
+) -> Result<rspotify::Token, AuthenticationError> {
+    if config.flow() == Flow::ClientCredentials {
+        return Err(AuthenticationError::ConfigError(
+            "The client credentials flow doesn't need a login".to_string(),
+        ));
+    }
+    let spotify = client(config)?;
+    migrate_legacy_token(token)?;
+    auth_with_fresh_token(&spotify)?;
+    save_client_token(&spotify, token)?.ok_or_else(|| {
+        AuthenticationError::ConfigError("Spotify didn't return a token".to_string())
+    })
 }
@@ -1,208 +1,1774 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressIterator, ProgressStyle};
+use clap::Parser;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rspotify::{model, prelude::*, ClientResult};
+use spt::changes;
+use spt::cli::{self, Command};
+use spt::config::{self, Config};
 use spt::eventsourcing::domain;
 use spt::eventsourcing::eventstore::JSONEventStore;
 use spt::eventsourcing::prelude::*;
+use spt::filter;
 use spt::login;
+use spt::notify;
+use spt::pool;
+use spt::profile;
+use spt::query;
+use spt::report;
+use spt::schedule;
 use spt::types;
-use spt::Commands;
-use std::time::Instant;
+use std::collections::HashSet;
+use std::env;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-const DATA_DIR: &str = "data";
-const DATA_FILE: &str = "events";
-const USER_FILE: &str = "data/users.json";
+const JOBS_VAR: &str = "SPT_JOBS";
+const PLAYLIST_FIELDS_VAR: &str = "SPT_PLAYLIST_FIELDS";
+const ITEM_FIELDS_VAR: &str = "SPT_ITEM_FIELDS";
+const MARKET_VAR: &str = "SPT_MARKET";
+const CHECK_AVAILABILITY_VAR: &str = "SPT_CHECK_AVAILABILITY";
 
-const MAIN_STYLE: &str = "[{elapsed_precise}][{bar:40.green/white}][{pos:>3}/{len:3}]: {msg}";
-const LOWER_STYLE: &str = "          [{bar:40.green/white}][{pos:>3}/{len:3}]: {msg}";
-const PROGRESS_CHARS: &str = "=>-";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Spotify-Playlist-Tracker-v{}\n", VERSION);
+/// Exit code if the saved token can't be used and logging in needs a user
+const EXIT_REAUTH: i32 = 3;
 
-    let config = spt::Commands::build()?;
+fn main() {
+    let cli = cli::Cli::parse();
+    if let Err(err) = execute(&cli) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    // Authenticate with OAuth
-    let spotify = login::login()?;
+fn execute(cli: &cli::Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let command = cli.command();
+    let global = &cli.global;
+    let selection = profile::Selection::new(global.profile.as_deref(), global.all_profiles);
+    let profiles: Vec<(profile::Profile, Config)> = selection
+        .profiles()?
+        .into_iter()
+        .map(|profile| load_profile(profile, global))
+        .collect::<Result<_, _>>()?;
+    // `spt config check` reports the problems itself
+    if !matches!(command, Command::Config(_)) {
+        for (profile, config) in profiles.iter() {
+            let problems = config.validate(&profile.env);
+            if !problems.is_empty() {
+                return Err(format!(
+                    "Invalid config {}:\n  {}\nRun `spt config check` for details",
+                    profile.config.display(),
+                    problems.join("\n  ")
+                )
+                .into());
+            }
+        }
+    }
 
-    // Load Users
-    let before = Instant::now();
-    let users = spt::load_users(USER_FILE)?;
-    println!(
-        "Loaded {} users from {} in {:.2?}",
-        users.len(),
-        USER_FILE,
-        before.elapsed()
+    // Only the reports are printed to stdout in the json output mode
+    let json = matches!(command, Command::Run(_) | Command::Watch(_))
+        && profiles
+            .iter()
+            .any(|(_, config)| output_mode(global, config) == cli::OutputMode::Json);
+    if let Command::Run(_) | Command::Watch(_) | Command::Auth(_) = command {
+        if !global.quiet && !json {
+            println!("Spotify-Playlist-Tracker-v{}\n", VERSION);
+        }
+    }
+
+    if profiles.len() > 1 {
+        let single = match &command {
+            Command::Watch(_) => Some("watch"),
+            Command::Users(_) => Some("users"),
+            Command::Playlists(_) => Some("playlists"),
+            Command::History(_) => Some("history"),
+            Command::Show(_) => Some("show"),
+            Command::Diff(_) => Some("diff"),
+            Command::Export(_) => Some("export"),
+            Command::Auth(args) if args.file.is_some() => Some("auth <file>"),
+            _ => None,
+        };
+        if let Some(name) = single {
+            return Err(format!(
+                "`spt {}` works on a single profile, select one with --profile",
+                name
+            )
+            .into());
+        }
+    }
+    if let [(profile, config)] = profiles.as_slice() {
+        return match run(profile, config, &command, global) {
+            Err(err) => match err.downcast_ref() {
+                Some(login::AuthenticationError::ReauthenticationRequired(why)) => {
+                    reauth_hint(profile, why);
+                    std::process::exit(EXIT_REAUTH);
+                }
+                _ => match err.downcast_ref::<report::PartialFailure>() {
+                    Some(failure) => {
+                        eprintln!("Error: {}", failure);
+                        std::process::exit(report::EXIT_PARTIAL);
+                    }
+                    None => Err(err),
+                },
+            },
+            ok => ok,
+        };
+    }
+
+    // Run every profile, a failing one doesn't stop the others
+    let mut failed = 0;
+    let mut partial = 0;
+    let mut reauth = false;
+    for (profile, config) in profiles.iter() {
+        if !json {
+            println!("\n=== Profile {} ===\n", profile);
+        }
+        match run(profile, config, &command, global) {
+            Ok(()) => (),
+            Err(err) => {
+                failed += 1;
+                match err.downcast_ref() {
+                    Some(login::AuthenticationError::ReauthenticationRequired(why)) => {
+                        reauth = true;
+                        reauth_hint(profile, why);
+                    }
+                    _ => {
+                        if err.is::<report::PartialFailure>() {
+                            partial += 1;
+                        }
+                        eprintln!("Profile {} failed: {}", profile, err)
+                    }
+                }
+            }
+        }
+    }
+    if reauth {
+        std::process::exit(EXIT_REAUTH);
+    }
+    if failed > 0 {
+        let why = format!("{} of {} profiles failed", failed, profiles.len());
+        // Profiles that ran with some failures only count as a partial failure
+        if failed == partial {
+            eprintln!("Error: {}", why);
+            std::process::exit(report::EXIT_PARTIAL);
+        }
+        return Err(why.into());
+    }
+    Ok(())
+}
+
+/// Reads the config file of the profile and applies it and the command line options
+fn load_profile(
+    mut profile: profile::Profile,
+    global: &cli::GlobalArgs,
+) -> Result<(profile::Profile, Config), types::SPTError> {
+    if let Some(path) = &global.config {
+        profile.config = path.clone();
+    }
+    let mut config = Config::load(&profile.config, global.config.is_some())?;
+    // A credentials file given on the command line replaces the ones in the config
+    if global.credentials.is_some() {
+        config.credentials = None;
+    }
+    let profile = profile
+        .with_config(&config)
+        .with_overrides(global.data_dir.as_deref(), global.credentials.as_deref());
+    Ok((profile, config))
+}
+
+/// Output mode of the command line (or `SPT_OUTPUT`), the config or the terminal
+fn output_mode(global: &cli::GlobalArgs, config: &Config) -> cli::OutputMode {
+    global
+        .output
+        .or(config.output.mode)
+        .unwrap_or_else(cli::OutputMode::detect)
+}
+
+/// Settings of a profile from the config file, overridden by the environment
+#[derive(Debug, Clone)]
+struct Settings {
+    jobs: usize,
+    fetch: types::FetchOptions,
+    /// Market as configured, for display
+    market: Option<String>,
+    interval: Duration,
+    progress: config::Progress,
+    output: cli::OutputMode,
+    notify: Vec<notify::Sink>,
+    /// Compare the playlists without recording the events
+    dry_run: bool,
+}
+
+impl Settings {
+    fn new(
+        config: &Config,
+        global: &cli::GlobalArgs,
+    ) -> Result<Settings, Box<dyn std::error::Error>> {
+        let jobs = match env::var(JOBS_VAR) {
+            Ok(jobs) => pool::parse_jobs(&jobs).map_err(|why| format!("{}: {}", JOBS_VAR, why))?,
+            Err(_) => config
+                .fetch
+                .jobs
+                .filter(|jobs| *jobs > 0)
+                .unwrap_or(pool::DEFAULT_JOBS),
+        };
+        let market = env::var(MARKET_VAR)
+            .ok()
+            .or_else(|| config.fetch.market.clone());
+        let interval = match &config.watch.interval {
+            Some(interval) => schedule::parse_interval(interval)?,
+            None => DEFAULT_INTERVAL,
+        };
+        Ok(Settings {
+            jobs,
+            fetch: fetch_options(config, market.as_deref())?,
+            market,
+            interval,
+            progress: config.progress.clone(),
+            output: output_mode(global, config),
+            notify: config.notify.clone(),
+            dry_run: false,
+        })
+    }
+
+    /// The global options for a run, the json output mode only prints the report
+    fn global(&self, global: &cli::GlobalArgs) -> cli::GlobalArgs {
+        match self.output {
+            cli::OutputMode::Json => cli::GlobalArgs {
+                quiet: true,
+                verbose: 0,
+                ..global.clone()
+            },
+            _ => global.clone(),
+        }
+    }
+
+    /// Styles of the main and the lower progress bar
+    fn styles(&self) -> Result<(ProgressStyle, ProgressStyle), indicatif::style::TemplateError> {
+        let chars = self.progress.chars();
+        Ok((
+            ProgressStyle::with_template(self.progress.main())?.progress_chars(chars),
+            ProgressStyle::with_template(self.progress.lower())?.progress_chars(chars),
+        ))
+    }
+}
+
+fn reauth_hint(profile: &profile::Profile, why: &str) {
+    let option = match &profile.name {
+        Some(name) => format!(" --profile {}", name),
+        None => String::new(),
+    };
+    eprintln!(
+        "Re-authentication required: {}\nRun `spt{} auth` in a terminal to log in again",
+        why, option
     );
+}
 
-    // Load stored events from file
+/// Runs the command for a single profile
+fn run(
+    profile: &profile::Profile,
+    config: &Config,
+    command: &Command,
+    global: &cli::GlobalArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Run(args) => {
+            let settings = Settings {
+                dry_run: args.dry_run,
+                ..Settings::new(config, global)?
+            };
+            update(profile, config, args, &settings.global(global), &settings)
+        }
+        Command::Watch(args) => {
+            let settings = Settings::new(config, global)?;
+            watch(profile, config, args, &settings.global(global), &settings)
+        }
+        Command::Auth(args) => {
+            let users = load_targets(profile, config, &[], false)
+                .map(|targets| targets.users)
+                .unwrap_or_default();
+            export_token(
+                profile,
+                &oauth_config(profile, config, &users)?,
+                args.file.as_deref(),
+            )
+        }
+        Command::Config(cli::ConfigCommand::Check) => check_config(profile, config, global),
+        Command::Config(cli::ConfigCommand::Show) => show_config(profile, config, global),
+        Command::Users(command) => manage_users(profile, config, command),
+        Command::Playlists(command) => manage_playlists(profile, command),
+        Command::Unavailable => Ok(print_unavailable(&open_store(profile)?)?),
+        Command::History(args) => {
+            let store = open_store(profile)?;
+            print_history(&store, &query::find_playlist(&store, &args.playlist)?, args)
+        }
+        Command::Show(args) => {
+            let store = open_store(profile)?;
+            let id = query::find_playlist(&store, &args.playlist)?;
+            match args.at {
+                Some(at) => {
+                    let state = spt::build_at(&id, &store, at)?;
+                    if state.generation == 0 {
+                        return Err(format!(
+                            "{} wasn't tracked yet at {}",
+                            id,
+                            at.format("%Y-%m-%d %H:%M:%S")
+                        )
+                        .into());
+                    }
+                    println!("As of {}", at.format("%Y-%m-%d %H:%M:%S"));
+                    print_playlist(&state);
+                }
+                None => print_playlist(&spt::build_local(&id, &store)?),
+            }
+            Ok(())
+        }
+        Command::Diff(args) => {
+            let store = open_store(profile)?;
+            let id = query::find_playlist(&store, &args.playlist)?;
+            if let (Some(from), Some(to)) = (args.from, args.to) {
+                if from > to {
+                    return Err(format!(
+                        "--from {} is after --to {}",
+                        from.format("%Y-%m-%d %H:%M:%S"),
+                        to.format("%Y-%m-%d %H:%M:%S")
+                    )
+                    .into());
+                }
+            }
+            let to = match args.to {
+                Some(at) => match spt::build_at(&id, &store, at)? {
+                    state if state.generation == 0 => {
+                        return Err(format!(
+                            "{} wasn't tracked yet at {}",
+                            id,
+                            at.format("%Y-%m-%d %H:%M:%S")
+                        )
+                        .into())
+                    }
+                    state => state,
+                },
+                None => spt::build_local(&id, &store)?,
+            };
+            let from = match args.from {
+                Some(at) => match spt::build_at(&id, &store, at)? {
+                    state if state.generation == 0 => {
+                        eprintln!(
+                            "{} wasn't tracked yet at {}, comparing with its first state",
+                            id,
+                            at.format("%Y-%m-%d %H:%M:%S")
+                        );
+                        query::first_state(&store, &id)?
+                    }
+                    state => state,
+                },
+                None => query::first_state(&store, &id)?,
+            };
+            let diff = query::diff(&from, &to);
+            match output_mode(global, config) {
+                cli::OutputMode::Json => {
+                    let output = DiffOutput {
+                        id: &id,
+                        name: &to.data.name,
+                        from: args.from,
+                        to: args.to,
+                        changes: &diff,
+                    };
+                    println!("{}", serde_json::to_string(&output)?);
+                }
+                mode => print_diff(&to, &diff, mode == cli::OutputMode::Tty),
+            }
+            Ok(())
+        }
+        Command::Export(args) => export(&open_store(profile)?, args),
+        Command::Verify => verify(&open_store(profile)?),
+        Command::Stats => print_stats(&open_store(profile)?),
+    }
+}
+
+/// What a run fetches
+#[derive(Debug, Clone)]
+struct Targets {
+    users: Vec<types::TrackedUser>,
+    playlists: Vec<types::TrackedPlaylist>,
+    /// Global filters, of the filter file and the config
+    filters: Vec<filter::Matcher>,
+}
+
+/// Loads the users, the playlists tracked on their own and the filters of the profile and its
+/// config. If users are selected only those are fetched, without the tracked playlists.
+fn load_targets(
+    profile: &profile::Profile,
+    config: &Config,
+    selected: &[String],
+    report: bool,
+) -> Result<Targets, Box<dyn std::error::Error>> {
+    // Load Users, the users file is optional if the config lists users
     let before = Instant::now();
-    let store_path = format!("{}/{}.json", DATA_DIR, DATA_FILE);
-    let event_store = JSONEventStore::from_file(&store_path);
-    let event_store = match event_store {
+    let mut users = config.users.clone();
+    match spt::load_users(&profile.users) {
+        Ok(loaded) => users.extend(loaded),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !users.is_empty() => (),
+        Err(err) => return Err(err.into()),
+    }
+    spt::dedup_users(&mut users);
+    if report {
+        println!(
+            "Loaded {} users from {} in {:.2?}",
+            users.len(),
+            profile.users.display(),
+            before.elapsed()
+        );
+    }
+    let users: Vec<types::TrackedUser> = if selected.is_empty() {
+        users
+    } else {
+        selected
+            .iter()
+            .map(|query| {
+                spt::find_user(&users, query)
+                    .cloned()
+                    .ok_or_else(|| types::SPTError::Lookup(format!("Unknown user {}", query)))
+            })
+            .collect::<Result<Vec<types::TrackedUser>, types::SPTError>>()?
+    };
+    // Selecting users skips the playlists tracked on their own
+    let playlists = match selected.is_empty() {
+        true => {
+            let mut playlists = config.playlists.clone();
+            for playlist in spt::load_playlists(&profile.playlists)? {
+                if !playlists.iter().any(|known| known.id == playlist.id) {
+                    playlists.push(playlist);
+                }
+            }
+            playlists
+        }
+        false => vec![],
+    };
+    if report && !playlists.is_empty() {
+        println!(
+            "Loaded {} playlists from {}",
+            playlists.len(),
+            profile.playlists.display()
+        );
+    }
+
+    let filter = filter::load(&profile.filter)
+        .and_then(|filter| filter.compile())
+        .map_err(|why| format!("Invalid filter in {}: {}", profile.filter.display(), why))?;
+    let filters = match config.filter.is_default() {
+        true => vec![filter],
+        false => vec![filter, config.filter.compile()?],
+    };
+    Ok(Targets {
+        users,
+        playlists,
+        filters,
+    })
+}
+
+/// Loads the events of the profile, a store that can't be read is replaced by a new one
+fn load_store(profile: &profile::Profile, global: &cli::GlobalArgs) -> JSONEventStore {
+    let before = Instant::now();
+    let store_path = &profile.events;
+    match JSONEventStore::from_file(&store_path) {
         Ok(store) => {
-            println!(
-                "Loaded {} events from {} in {:.2?}",
-                store.len(),
-                store_path,
-                before.elapsed()
-            );
+            if !global.quiet {
+                println!(
+                    "Loaded {} events from {} in {:.2?}",
+                    store.len(),
+                    store_path.display(),
+                    before.elapsed()
+                );
+            }
             store
         }
         Err(err) => {
             eprintln!(
                 "Failed to create eventstore from {}: {}\nUsing new one instead...",
-                store_path, err
+                store_path.display(),
+                err
             );
             JSONEventStore::new()
         }
+    }
+}
+
+/// Writes the events of the profile to disk
+fn save_store(profile: &profile::Profile, event_store: &JSONEventStore, global: &cli::GlobalArgs) {
+    let before = Instant::now();
+    let store_path = &profile.events;
+    let saved = std::fs::create_dir_all(&profile.data_dir)
+        .map_err(types::SPTError::from)
+        .and_then(|_| event_store.save_events(store_path));
+    match saved {
+        Ok(_) => {
+            if !global.quiet {
+                println!(
+                    "Saved all events to {} in {:.2?}",
+                    store_path.display(),
+                    before.elapsed()
+                )
+            }
+        }
+        Err(err) => eprintln!("Failed to save events to {}: {}", store_path.display(), err),
+    }
+}
+
+/// Fetches the playlists of the users and records the changes
+fn update(
+    profile: &profile::Profile,
+    config: &Config,
+    args: &cli::RunArgs,
+    global: &cli::GlobalArgs,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let targets = load_targets(profile, config, &args.users, !global.quiet)?;
+    let event_store = load_store(profile, global);
+
+    // Authenticate with OAuth
+    let client = login::connect(
+        &oauth_config(profile, config, &targets.users)?,
+        &profile.token,
+        login::AuthMode::detect(),
+        &profile.env,
+    )?;
+    let mut report = report::RunReport::new(&profile.to_string());
+    report.dry_run = settings.dry_run;
+    let started = Instant::now();
+    let events = event_store.len();
+    let tracked = track_client(
+        &client,
+        &targets,
+        &event_store,
+        global,
+        settings,
+        &mut report,
+    );
+    if tracked.is_ok() && settings.dry_run {
+        if !global.quiet {
+            println!("Dry run, no events were saved");
+        }
+    } else if tracked.is_ok() {
+        save_store(profile, &event_store, global);
+        send_notifications(&settings.notify, &event_store.events_since(events));
+    }
+
+    report.finish(started.elapsed());
+    if let Err(err) = &tracked {
+        report.errors.push(err.to_string());
+    }
+    print_report(settings, &report)?;
+    tracked?;
+    Ok(report.result()?)
+}
+
+/// Prints the report of a run as a JSON line in the json output mode
+fn print_report(settings: &Settings, report: &report::RunReport) -> Result<(), serde_json::Error> {
+    if settings.output == cli::OutputMode::Json {
+        println!("{}", serde_json::to_string(report)?);
+    }
+    Ok(())
+}
+
+/// Keeps the client and the events loaded and fetches every user and playlist once its
+/// interval passed, until SIGINT or SIGTERM. The users, playlists and filter are reloaded
+/// between runs, so changes apply without a restart.
+fn watch(
+    profile: &profile::Profile,
+    config: &Config,
+    args: &cli::WatchArgs,
+    global: &cli::GlobalArgs,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let interval = args.interval.unwrap_or(settings.interval);
+    let mut targets = load_targets(profile, config, &[], !global.quiet)?;
+    let mut intervals = schedule_intervals(&targets, interval)?;
+    let event_store = load_store(profile, global);
+    let client = login::connect(
+        &oauth_config(profile, config, &targets.users)?,
+        &profile.token,
+        login::AuthMode::detect(),
+        &profile.env,
+    )?;
+    // Installed after the login, so an interactive login can still be interrupted
+    let (stop, stopped) = mpsc::channel();
+    let mut interrupted = false;
+    ctrlc::set_handler(move || {
+        if interrupted {
+            eprintln!("Exiting without saving the events of the current run");
+            std::process::exit(130);
+        }
+        interrupted = true;
+        eprintln!("Stopping after the current run, interrupt again to exit right away");
+        let _ = stop.send(());
+    })?;
+
+    // The app token is requested again by rspotify itself once it expired
+    let (stop_refresh, refresh_stopped) = mpsc::channel();
+    let refresher = match &client {
+        login::Client::User(spotify) => Some(login::refresh_in_background(
+            spotify.clone(),
+            profile.token.clone(),
+            refresh_stopped,
+        )),
+        login::Client::App(_) => None,
     };
 
-    let users: Vec<types::User> = match config {
-        Commands::SINGLE => users[0..1].to_vec(),
-        Commands::DEFAULT => users,
-        Commands::AddUser(config) => {
-            let user = types::User {
-                display_name: Some(config[0].clone()),
-                id: rspotify::model::UserId::from_id(config[1].clone())
-                    .unwrap()
-                    .to_string(),
+    let mut schedule = schedule::Schedule::new();
+    loop {
+        let due: HashSet<String> = schedule
+            .due(&intervals, Instant::now())
+            .into_iter()
+            .collect();
+        if !due.is_empty() {
+            let run = Targets {
+                users: targets
+                    .users
+                    .iter()
+                    .filter(|user| due.contains(&user.id))
+                    .cloned()
+                    .collect(),
+                playlists: targets
+                    .playlists
+                    .iter()
+                    .filter(|playlist| due.contains(&playlist.id))
+                    .cloned()
+                    .collect(),
+                filters: targets.filters.clone(),
             };
-            spt::add_users(USER_FILE, user).unwrap();
-            vec![]
+            if !global.quiet {
+                println!(
+                    "\n[{}] Fetching {} users and {} playlists",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    run.users.len(),
+                    run.playlists.len()
+                );
+            }
+            let mut report = report::RunReport::new(&profile.to_string());
+            let started = Instant::now();
+            let events = event_store.len();
+            if let Err(err) =
+                track_client(&client, &run, &event_store, global, settings, &mut report)
+            {
+                eprintln!("Run failed: {}", err);
+                report.errors.push(err.to_string());
+            }
+            report.finish(started.elapsed());
+            if let Err(err) = print_report(settings, &report) {
+                eprintln!("Failed to print the report: {}", err);
+            }
+            if event_store.len() != events {
+                save_store(profile, &event_store, global);
+                send_notifications(&settings.notify, &event_store.events_since(events));
+            }
         }
+
+        let wait = schedule.next_due().map_or(interval, |next| {
+            next.saturating_duration_since(Instant::now())
+        });
+        match stopped.recv_timeout(wait) {
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            _ => break,
+        }
+
+        match load_targets(profile, config, &[], global.verbose > 0).and_then(|reloaded| {
+            let reloaded_intervals = schedule_intervals(&reloaded, interval)?;
+            Ok((reloaded, reloaded_intervals))
+        }) {
+            Ok(reloaded) => (targets, intervals) = reloaded,
+            Err(err) => eprintln!(
+                "Failed to reload the users and playlists, keeping the previous ones: {}",
+                err
+            ),
+        }
+    }
+
+    drop(stop_refresh);
+    if let Some(refresher) = refresher {
+        let _ = refresher.join();
+    }
+    save_store(profile, &event_store, global);
+    Ok(())
+}
+
+/// Interval of every user and tracked playlist, keyed by their URI
+fn schedule_intervals(
+    targets: &Targets,
+    default: Duration,
+) -> Result<Vec<(String, Duration)>, Box<dyn std::error::Error>> {
+    let interval = |label: &str, interval: Option<&str>| match interval {
+        Some(interval) => schedule::parse_interval(interval)
+            .map_err(|why| format!("Invalid interval of {}: {}", label, why)),
+        None => Ok(default),
     };
+    let mut intervals = Vec::new();
+    for user in targets.users.iter() {
+        let duration = interval(user.name_or_id(), user.interval.as_deref())?;
+        intervals.push((user.id.clone(), duration));
+    }
+    for playlist in targets.playlists.iter() {
+        let duration = interval(playlist.name_or_id(), playlist.interval.as_deref())?;
+        intervals.push((playlist.id.clone(), duration));
+    }
+    Ok(intervals)
+}
+
+/// Runs [`track`] with the client of the configured flow
+fn track_client(
+    client: &login::Client,
+    targets: &Targets,
+    event_store: &JSONEventStore,
+    global: &cli::GlobalArgs,
+    settings: &Settings,
+    report: &mut report::RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match client {
+        login::Client::User(spotify) => {
+            // Private and collaborative playlists are only listed for the logged in user
+            let own = if targets
+                .users
+                .iter()
+                .any(|user| !user.playlists.is_default())
+            {
+                let me = spotify.me()?;
+                let playlists: ClientResult<Vec<model::SimplifiedPlaylist>> =
+                    spotify.current_user_playlists().collect();
+                Some((me.id.to_string(), playlists?))
+            } else {
+                None
+            };
+            track(spotify, own, targets, event_store, global, settings, report)
+        }
+        login::Client::App(spotify) => track(
+            spotify,
+            None,
+            targets,
+            event_store,
+            global,
+            settings,
+            report,
+        ),
+    }
+}
+
+/// Where the messages of a run go, see [`cli::OutputMode`]
+struct Output {
+    mode: cli::OutputMode,
+    multi: MultiProgress,
+    /// Reports the changes found while comparing the playlists
+    reporter: Box<dyn changes::ChangeReporter>,
+    quiet: bool,
+}
+
+impl Output {
+    fn new(mode: cli::OutputMode, quiet: bool) -> Output {
+        let target = match mode {
+            cli::OutputMode::Tty if !quiet => ProgressDrawTarget::stderr_with_hz(120),
+            _ => ProgressDrawTarget::hidden(),
+        };
+        let multi = MultiProgress::with_draw_target(target);
+        let reporter: Box<dyn changes::ChangeReporter> = match mode {
+            cli::OutputMode::Tty => Box::new(changes::ProgressReporter::new(&multi)),
+            cli::OutputMode::Plain => Box::new(changes::LogReporter::new(std::io::stdout())),
+            cli::OutputMode::Json => Box::new(changes::LogReporter::new(std::io::stderr())),
+        };
+        Output {
+            mode,
+            multi,
+            reporter,
+            quiet,
+        }
+    }
+
+    /// Prints a line above the progress bars, or on its own without them
+    fn println(&self, line: String) {
+        match self.mode {
+            cli::OutputMode::Tty => self.multi.println(line).unwrap(),
+            cli::OutputMode::Plain => println!("{}", line),
+            cli::OutputMode::Json => eprintln!("{}", line),
+        }
+    }
+
+    /// Prints what a finished progress bar shows, for the plain output without bars
+    fn summary(&self, line: &str) {
+        if self.mode == cli::OutputMode::Plain && !self.quiet {
+            println!("{}", line);
+        }
+    }
+}
 
-    let target = ProgressDrawTarget::stderr_with_hz(120);
-    let multi = MultiProgress::with_draw_target(target);
-    let stylemain = ProgressStyle::with_template(MAIN_STYLE)?.progress_chars(PROGRESS_CHARS);
-    let style = ProgressStyle::with_template(LOWER_STYLE)?.progress_chars(PROGRESS_CHARS);
-    let pb = ProgressBar::new(users.len() as u64).with_style(stylemain);
+/// Fetches the playlists of all users and the tracked playlists, compares them with the stored
+/// state and appends the changes to the store. `own` are the playlists of the logged in user, if
+/// they are needed. Playlists rejected by the filter or the filter of their user are skipped.
+/// The outcome of every user and playlist is added to the report.
+fn track<C: BaseClient>(
+    spotify: &C,
+    own: Option<(String, Vec<model::SimplifiedPlaylist>)>,
+    targets: &Targets,
+    event_store: &JSONEventStore,
+    global: &cli::GlobalArgs,
+    settings: &Settings,
+    report: &mut report::RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (users, tracked) = (&targets.users, &targets.playlists);
+    let (jobs, fetch) = (settings.jobs, &settings.fetch);
+    let out = Output::new(settings.output, global.quiet);
+    let multi = &out.multi;
+    let (stylemain, style) = settings.styles()?;
+    let pb = ProgressBar::new((users.len() + tracked.len()) as u64).with_style(stylemain);
     let pb = multi.insert(0, pb);
+    pb.set_message(format!("Fetching playlists with {} workers", jobs));
     pb.tick();
-    let mut pbs: Vec<ProgressBar> = Vec::new();
 
-    for user in &users {
-        if !pbs.is_empty() {
-            for pb in &pbs {
-                multi.remove(pb);
+    let user_filters = users
+        .iter()
+        .map(|user| {
+            user.filter
+                .compile()
+                .map_err(|why| format!("Invalid filter of {}: {}", user.name_or_id(), why))
+        })
+        .collect::<Result<Vec<filter::Matcher>, String>>()?;
+
+    // Filtered out playlists are skipped, but they are reported if they were tracked before
+    let stored: HashSet<String> = event_store.origin_ids().into_iter().collect();
+    let passes = |label: &str, playlist: &model::SimplifiedPlaylist, rules: &[&filter::Matcher]| {
+        let why = match rules.iter().find_map(|rules| rules.rejects(playlist)) {
+            Some(why) => why,
+            None => return true,
+        };
+        if stored.contains(&playlist.id.to_string()) {
+            if !global.quiet {
+                out.println(format!(
+                    "[{}] Untracked {} ( {} ): {}",
+                    label, playlist.name, playlist.id, why
+                ));
             }
-            pbs.clear();
+        } else if global.verbose > 0 {
+            out.println(format!(
+                "[{}] Skipped {} ( {} ): {}",
+                label, playlist.name, playlist.id, why
+            ));
         }
+        false
+    };
 
-        let nameorid = user.name_or_id();
-        pb.set_message(format!("{}", nameorid));
-        pb.set_prefix(format!("{}", nameorid));
+    // Fetch the playlists of all users in parallel
+    let before = Instant::now();
+    let user_playlists: Vec<(
+        &types::TrackedUser,
+        types::FetchOptions,
+        Vec<model::SimplifiedPlaylist>,
+        report::UserOutcome,
+    )> = pool::map(
+        jobs,
+        users.iter().zip(user_filters.iter()).collect(),
+        |(user, user_filter)| {
+            let started = Instant::now();
+            let options = market_options(&out, user.name_or_id(), user.market.as_deref(), fetch);
+            let mut error = None;
+            let playlists: Vec<ClientResult<model::SimplifiedPlaylist>> =
+                match model::UserId::from_id_or_uri(&user.id) {
+                    Ok(_) if own.as_ref().is_some_and(|(me, _)| *me == user.id) => own
+                        .iter()
+                        .flat_map(|(_, playlists)| playlists.iter().cloned().map(Ok))
+                        .collect(),
+                    Ok(id) => {
+                        if !user.playlists.is_default() {
+                            out.println(format!(
+                                "[{}] Private and collaborative playlists are only visible for the logged in user",
+                                user.name_or_id()
+                            ));
+                        }
+                        spotify.user_playlists(id).collect()
+                    }
+                    Err(why) => {
+                        let why = format!("Invalid user id {}: {}", user.id, why);
+                        out.println(format!("[{}] {}", user.name_or_id(), why));
+                        error = Some(why);
+                        vec![]
+                    }
+                };
+            // The playlists fetched before an error are still tracked
+            let (playlists, failed): (Vec<_>, Vec<_>) =
+                playlists.into_iter().partition(Result::is_ok);
+            if let Some(Err(why)) = failed.into_iter().next() {
+                let why = format!("Failed to fetch playlists: {}", why);
+                out.println(format!("[{}] {}", user.name_or_id(), why));
+                error = Some(why);
+            }
+            let playlists: Vec<model::SimplifiedPlaylist> = playlists
+                .into_iter()
+                .flatten()
+                .filter(|pl| user.playlists.tracks(&user.id, pl)) // filter out playlists the user doesn't want to track (e.g. the Daily Mix etc.)
+                .collect();
+            let total = playlists.len();
+            let playlists: Vec<model::SimplifiedPlaylist> = playlists
+                .into_iter()
+                .filter(|pl| {
+                    let rules: Vec<&filter::Matcher> =
+                        targets.filters.iter().chain([user_filter]).collect();
+                    passes(user.name_or_id(), pl, &rules)
+                })
+                .collect();
+            pb.set_message(format!("Fetched playlists of {}", user.name_or_id()));
+            pb.inc(1);
+            let outcome = report::UserOutcome {
+                id: user.id.clone(),
+                name: user.name_or_id().to_string(),
+                playlists: playlists.len(),
+                skipped: total - playlists.len(),
+                duration_ms: started.elapsed().as_millis(),
+                error,
+            };
+            (user, options, playlists, outcome)
+        },
+    );
 
-        // Build playlists from spotify data
-        let user_playlists: Vec<ClientResult<model::SimplifiedPlaylist>> = spotify
-            .user_playlists(model::UserId::from_id_or_uri(&user.id)?)
-            .collect();
+    // Fetch the tracked playlists that aren't already covered by a user
+    let covered: HashSet<String> = user_playlists
+        .iter()
+        .flat_map(|(_, _, playlists, _)| playlists.iter().map(|pl| pl.id.to_string()))
+        .collect();
+    let tracked: Vec<&types::TrackedPlaylist> = tracked
+        .iter()
+        .filter(|playlist| {
+            let covered = covered.contains(&playlist.id);
+            if covered {
+                if global.verbose > 0 {
+                    out.println(format!(
+                        "[{}] Already tracked through its owner",
+                        playlist.name_or_id()
+                    ));
+                }
+                pb.inc(1);
+            }
+            !covered
+        })
+        .collect();
+    let tracked_playlists: Vec<(
+        types::FetchOptions,
+        Option<model::SimplifiedPlaylist>,
+        Option<report::PlaylistOutcome>,
+    )> = pool::map(jobs, tracked, |playlist| {
+        let started = Instant::now();
+        let options = market_options(
+            &out,
+            playlist.name_or_id(),
+            playlist.market.as_deref(),
+            fetch,
+        );
+        let summary = model::PlaylistId::from_id_or_uri(&playlist.id)
+            .map_err(|why| why.to_string())
+            .and_then(|id| {
+                types::Playlist::summary(spotify, id, &options).map_err(|why| why.to_string())
+            });
+        pb.set_message(format!("Fetched playlist {}", playlist.name_or_id()));
+        pb.inc(1);
+        let outcome = |status, error| report::PlaylistOutcome {
+            id: playlist.id.clone(),
+            name: playlist.name_or_id().to_string(),
+            owner: String::new(),
+            status,
+            events: Default::default(),
+            pending: vec![],
+            duration_ms: started.elapsed().as_millis(),
+            error,
+        };
+        match summary {
+            Ok(summary) => {
+                let rules: Vec<&filter::Matcher> = targets.filters.iter().collect();
+                match passes(playlist.name_or_id(), &summary, &rules) {
+                    true => (options, Some(summary), None),
+                    false => (options, None, Some(outcome(report::Status::Filtered, None))),
+                }
+            }
+            Err(why) => {
+                let why = format!("Failed to fetch playlist {}: {}", playlist.id, why);
+                out.println(format!("[{}] {}", playlist.name_or_id(), why));
+                (
+                    options,
+                    None,
+                    Some(outcome(report::Status::Failed, Some(why))),
+                )
+            }
+        }
+    });
+    let fetched = format!(
+        "Fetched playlists of {} users and {} tracked playlists in {:.2?}",
+        users.len(),
+        tracked_playlists.len(),
+        before.elapsed()
+    );
+    report.fetch_ms = before.elapsed().as_millis();
+    pb.finish_with_message(fetched.clone());
+    out.summary(&fetched);
 
-        let pb1 = ProgressBar::new(user_playlists.len() as u64).with_style(style.clone());
-        let pb1 = multi.insert(1, pb1);
-        pb1.set_message("Building playlists from spotify data");
-        pbs.push(pb1.clone());
-        pb1.tick();
-        let before = Instant::now();
-        let user_playlists: Vec<model::SimplifiedPlaylist> = user_playlists
-            .into_iter()
-            .progress_with(pb1.clone())
-            .flatten() // throw away Result:Err(_) entries
-            .filter(|pl| pl.owner.id.to_string() == user.id) // filter out all playlists not owned by the user (e.g. the Daily Mix etc.)
-            .collect();
-        let playlists = &user_playlists;
-        pb1.finish_with_message(format!(
-            "Built playlists from spotify data in {:.2?}",
-            before.elapsed()
-        ));
+    // Rebuild, compare and update all playlists in parallel. Tracked playlists are labeled
+    // with their owner like the playlists of the users.
+    let playlists: Vec<(&str, &types::FetchOptions, model::SimplifiedPlaylist)> = user_playlists
+        .iter()
+        .flat_map(|(user, options, playlists, _)| {
+            playlists
+                .iter()
+                .map(move |pl| (user.name_or_id(), options, pl.clone()))
+        })
+        .chain(
+            tracked_playlists
+                .iter()
+                .filter_map(|(options, playlist, _)| {
+                    let playlist = playlist.as_ref()?;
+                    let owner = playlist.owner.display_name.as_deref();
+                    Some((
+                        owner.unwrap_or(playlist.owner.id.id()),
+                        options,
+                        playlist.clone(),
+                    ))
+                }),
+        )
+        .collect();
+    let pb1 = ProgressBar::new(playlists.len() as u64).with_style(style);
+    let pb1 = multi.insert(1, pb1);
+    pb1.set_message("Comparing playlists");
+    pb1.tick();
+    let before = Instant::now();
+    let compared = pool::map(jobs, playlists, |(username, options, playlist)| {
+        pb1.set_message(format!("[{}] {}", username, playlist.name));
+        let started = Instant::now();
 
         // Rebuild playlist state from events
-        let pb2 = ProgressBar::new(user_playlists.len() as u64).with_style(style.clone());
-        let pb2 = multi.insert(2, pb2);
-        pb2.set_message("Rebuilding playlists from memory");
-        pbs.push(pb2.clone());
-        pb2.tick();
-        let before = Instant::now();
-        let localplaylists: Vec<domain::PlaylistData> = user_playlists
-            .iter()
-            .progress_with(pb2.clone())
-            .map(|pl| spt::build_local(&pl.id.to_string(), &event_store).unwrap())
-            .collect();
-        pb2.finish_with_message(format!(
-            "Rebuilt playlists from memory in {:.2?}",
-            before.elapsed()
-        ));
+        let local = spt::build_local(&playlist.id.to_string(), event_store).unwrap();
+        let created = local.generation == 0;
 
         // Compare Playlists
-        let pb3 = ProgressBar::new(user_playlists.len() as u64).with_style(style.clone());
-        let pb3 = multi.insert(3, pb3);
-        pb3.set_message("Comparing playlists");
-        pbs.push(pb3.clone());
-        pb3.tick();
-        let before = Instant::now();
-        for (playlist, local) in playlists.iter().zip(localplaylists.iter()) {
-            let plevent = spt::compare(
-                &user.display_name.clone().unwrap_or(user.id.clone()),
-                &multi,
-                &spotify,
-                &local,
-                &playlist,
-                None,
-                None,
-            );
-            match plevent {
-                Ok(plevent) => {
-                    if !plevent.is_empty() {
-                        // Calculate new state
-                        let _state =
-                            domain::PlaylistAggregate::apply_all(local.clone(), &plevent).unwrap();
+        let mut outcome = report::PlaylistOutcome {
+            id: playlist.id.to_string(),
+            name: playlist.name.clone(),
+            owner: username.to_string(),
+            status: report::Status::Unchanged,
+            events: Default::default(),
+            pending: vec![],
+            duration_ms: 0,
+            error: None,
+        };
+        // A dry run prints the pending changes itself, once they are known
+        let reporter: &dyn changes::ChangeReporter = match settings.dry_run {
+            true => &changes::NoopReporter,
+            false => out.reporter.as_ref(),
+        };
+        match spt::compare(username, reporter, spotify, &local, &playlist, options) {
+            Ok(plevent) => {
+                if plevent.is_empty() && global.verbose > 0 {
+                    out.println(format!(
+                        "[{}] Unchanged {} ( {} )",
+                        username, playlist.name, playlist.id
+                    ));
+                }
+                if !plevent.is_empty() {
+                    outcome.status = match created {
+                        true => report::Status::Created,
+                        false => report::Status::Changed,
+                    };
+                    // Calculate new state
+                    let state =
+                        domain::PlaylistAggregate::apply_all(local.clone(), &plevent).unwrap();
 
+                    if settings.dry_run {
+                        let colored = settings.output == cli::OutputMode::Tty;
+                        let lines = pending_lines(username, &local, &state, &plevent, colored);
+                        out.println(lines.join("\n"));
+                        outcome.pending = plevent;
+                    } else {
                         // Save all events
                         for event in plevent {
-                            let _store_result =
-                                event_store.append(event.clone(), "playlists").unwrap();
+                            *outcome.events.entry(event.name().to_string()).or_default() += 1;
+                            let _store_result = event_store.append(event, "playlists").unwrap();
                         }
                     }
                 }
-                Err(why) => {
-                    multi
-                        .println(format!(
-                            "[{}] Failed to compare playlist {} ( {} ): {}",
-                            &user.display_name.clone().unwrap_or(user.id.clone()),
-                            playlist.name,
-                            playlist.id,
-                            why
-                        ))
-                        .unwrap();
+            }
+            Err(why) => {
+                out.println(format!(
+                    "[{}] Failed to compare playlist {} ( {} ): {}",
+                    username, playlist.name, playlist.id, why
+                ));
+                outcome.status = report::Status::Failed;
+                outcome.error = Some(why.to_string());
+            }
+        }
+        pb1.inc(1);
+        outcome.duration_ms = started.elapsed().as_millis();
+        outcome
+    });
+    let compared_in = format!("Compared and updated playlists in {:.2?}", before.elapsed());
+    report.compare_ms = before.elapsed().as_millis();
+    pb1.finish_with_message(compared_in.clone());
+    out.summary(&compared_in);
+    pb.tick();
+
+    report
+        .users
+        .extend(user_playlists.into_iter().map(|(_, _, _, outcome)| outcome));
+    report.playlists.extend(
+        tracked_playlists
+            .into_iter()
+            .filter_map(|(_, _, outcome)| outcome),
+    );
+    report.playlists.extend(compared);
+    Ok(())
+}
+
+/// Fetch options with the market of a user or tracked playlist, an invalid market falls back to
+/// the global one
+fn market_options(
+    out: &Output,
+    label: &str,
+    market: Option<&str>,
+    fetch: &types::FetchOptions,
+) -> types::FetchOptions {
+    match market {
+        Some(market) => match types::parse_market(market) {
+            Ok(market) => types::FetchOptions {
+                market: Some(market),
+                ..fetch.clone()
+            },
+            Err(why) => {
+                out.println(format!(
+                    "[{}] Invalid market {}, using the global one instead: {}",
+                    label, market, why
+                ));
+                fetch.clone()
+            }
+        },
+        None => fetch.clone(),
+    }
+}
+
+/// Loads the OAuth settings of the profile, from the config or the credentials file, and adds
+/// the scopes the playlist policies of the users need
+fn oauth_config(
+    profile: &profile::Profile,
+    config: &Config,
+    users: &[types::TrackedUser],
+) -> Result<login::OAuthConfig, login::AuthenticationError> {
+    let scopes: Vec<&str> = users
+        .iter()
+        .flat_map(|user| user.playlists.scopes())
+        .collect();
+    let oauth = match &config.credentials {
+        Some(credentials) => credentials.clone().with_env(&profile.env)?,
+        None => login::OAuthConfig::load_from(&profile.credentials, &profile.env)?,
+    };
+    Ok(oauth.with_scopes(&scopes))
+}
+
+/// Logs in interactively and exports the token for headless runs
+fn export_token(
+    profile: &profile::Profile,
+    config: &login::OAuthConfig,
+    path: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = login::authenticate(config, &profile.token)?;
+    if let Some(path) = path {
+        // Same protection as the saved token, it holds the refresh token
+        spt::token::save(&token, path, login::token_passphrase().as_deref())?;
+        println!("Saved token to {}", path.display());
+    }
+    match token.refresh_token {
+        Some(refresh_token) => println!("SPT_REFRESH_TOKEN={}", refresh_token),
+        None => eprintln!("Spotify didn't return a refresh token"),
+    }
+    Ok(())
+}
+
+/// Adds, removes or lists the users of the profile
+fn manage_users(
+    profile: &profile::Profile,
+    config: &Config,
+    command: &cli::UsersCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        cli::UsersCommand::Add { id, name } => {
+            let id = model::UserId::from_id_or_uri(id)
+                .map_err(|why| format!("Invalid user id {}: {}", id, why))?;
+            let user = types::TrackedUser {
+                display_name: name.clone(),
+                id: id.to_string(),
+                ..Default::default()
+            };
+            spt::add_users(&profile.users, user.clone())?;
+            println!("Added {} ( {} )", user.name_or_id(), user.id);
+        }
+        cli::UsersCommand::Remove { user } => {
+            let mut users = spt::load_users(&profile.users)?;
+            let removed = spt::find_user(&users, user)
+                .cloned()
+                .ok_or_else(|| types::SPTError::Lookup(format!("Unknown user {}", user)))?;
+            users.retain(|user| user.id != removed.id);
+            spt::save_users(&profile.users, &users)?;
+            println!("Removed {} ( {} )", removed.name_or_id(), removed.id);
+        }
+        cli::UsersCommand::Rename { user, name } => {
+            let mut users = spt::load_users(&profile.users)?;
+            let id = spt::find_user(&users, user)
+                .map(|user| user.id.clone())
+                .ok_or_else(|| types::SPTError::Lookup(format!("Unknown user {}", user)))?;
+            for user in users.iter_mut().filter(|user| user.id == id) {
+                user.display_name = Some(name.clone());
+            }
+            spt::save_users(&profile.users, &users)?;
+            println!("Renamed {} to {}", id, name);
+        }
+        cli::UsersCommand::List => {
+            for user in spt::load_users(&profile.users)? {
+                let mut details = Vec::new();
+                if let Some(market) = &user.market {
+                    details.push(format!("market {}", market));
+                }
+                if user.playlists.private {
+                    details.push("private".to_string());
+                }
+                if user.playlists.collaborative {
+                    details.push("collaborative".to_string());
+                }
+                match details.is_empty() {
+                    true => println!("{} ( {} )", user.name_or_id(), user.id),
+                    false => println!(
+                        "{} ( {} ) [{}]",
+                        user.name_or_id(),
+                        user.id,
+                        details.join(", ")
+                    ),
                 }
             }
-            pb3.inc(1);
-            pb3.tick();
         }
-        pb3.finish_with_message(format!(
-            "Compared and updated playlists in {:.2?}",
-            before.elapsed()
+        cli::UsersCommand::Validate { remove_invalid } => {
+            let users = spt::load_users(&profile.users)?;
+            let client = login::connect(
+                &oauth_config(profile, config, &users)?,
+                &profile.token,
+                login::AuthMode::detect(),
+                &profile.env,
+            )?;
+            match &client {
+                login::Client::User(spotify) => {
+                    validate_users(spotify, profile, users, *remove_invalid)?
+                }
+                login::Client::App(spotify) => {
+                    validate_users(spotify, profile, users, *remove_invalid)?
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds, removes or lists the playlists tracked on their own
+fn manage_playlists(
+    profile: &profile::Profile,
+    command: &cli::PlaylistsCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        cli::PlaylistsCommand::Add {
+            playlist,
+            name,
+            market,
+            interval,
+        } => {
+            let id = query::playlist_uri(playlist)
+                .ok_or_else(|| format!("Invalid playlist id {}", playlist))?;
+            if let Some(market) = market {
+                types::parse_market(market)?;
+            }
+            if let Some(interval) = interval {
+                schedule::parse_interval(interval)?;
+            }
+            let playlist = types::TrackedPlaylist {
+                id,
+                name: name.clone(),
+                market: market.clone(),
+                interval: interval.clone(),
+            };
+            spt::add_playlists(&profile.playlists, playlist.clone())?;
+            println!("Added {} ( {} )", playlist.name_or_id(), playlist.id);
+        }
+        cli::PlaylistsCommand::Remove { playlist } => {
+            let mut playlists = spt::load_playlists(&profile.playlists)?;
+            let removed = spt::find_tracked_playlist(&playlists, playlist)
+                .cloned()
+                .ok_or_else(|| types::SPTError::Lookup(format!("Unknown playlist {}", playlist)))?;
+            playlists.retain(|playlist| playlist.id != removed.id);
+            spt::save_playlists(&profile.playlists, &playlists)?;
+            println!("Removed {} ( {} )", removed.name_or_id(), removed.id);
+        }
+        cli::PlaylistsCommand::List => {
+            for playlist in spt::load_playlists(&profile.playlists)? {
+                match &playlist.market {
+                    Some(market) => println!(
+                        "{} ( {} ) [market {}]",
+                        playlist.name_or_id(),
+                        playlist.id,
+                        market
+                    ),
+                    None => println!("{} ( {} )", playlist.name_or_id(), playlist.id),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the users against Spotify and saves the fixed list
+fn validate_users<C: BaseClient>(
+    spotify: &C,
+    profile: &profile::Profile,
+    mut users: Vec<types::TrackedUser>,
+    remove_invalid: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changed = false;
+    for duplicate in spt::dedup_users(&mut users) {
+        println!(
+            "Removed duplicate {} ( {} )",
+            duplicate.name_or_id(),
+            duplicate.id
+        );
+        changed = true;
+    }
+
+    let mut invalid = Vec::new();
+    let mut unchecked = 0;
+    for user in users.iter_mut() {
+        match spt::validate_user(spotify, user) {
+            Ok(true) => {
+                println!("Updated {} ( {} )", user.name_or_id(), user.id);
+                changed = true;
+            }
+            Ok(false) => println!("Valid {} ( {} )", user.name_or_id(), user.id),
+            // Only a user that doesn't exist is invalid, an outage or rate limit isn't
+            Err(why) if why.is_not_found() || matches!(why, types::SPTError::Lookup(_)) => {
+                eprintln!("Invalid {} ( {} ): {}", user.name_or_id(), user.id, why);
+                invalid.push(user.id.clone());
+            }
+            Err(why) => {
+                eprintln!(
+                    "Couldn't check {} ( {} ): {}",
+                    user.name_or_id(),
+                    user.id,
+                    why
+                );
+                unchecked += 1;
+            }
+        }
+    }
+    if remove_invalid && !invalid.is_empty() {
+        users.retain(|user| !invalid.contains(&user.id));
+        println!("Removed {} invalid users", invalid.len());
+        changed = true;
+        invalid.clear();
+    }
+    // Normalizing an id could have revealed more duplicates
+    changed |= !spt::dedup_users(&mut users).is_empty();
+
+    if changed {
+        spt::save_users(&profile.users, &users)?;
+        println!("Saved {} users to {}", users.len(), profile.users.display());
+    }
+    if !invalid.is_empty() {
+        return Err(format!(
+            "{} users are invalid, remove them with --remove-invalid",
+            invalid.len()
+        )
+        .into());
+    }
+    if unchecked > 0 {
+        return Err(format!(
+            "{} users couldn't be checked and were kept, try again later",
+            unchecked
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Loads the events of the profile for the read-only commands, a missing store is empty
+fn open_store(profile: &profile::Profile) -> Result<JSONEventStore, types::SPTError> {
+    match JSONEventStore::from_file(&profile.events) {
+        Err(types::SPTError::IO(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(JSONEventStore::new())
+        }
+        store => store,
+    }
+}
+
+fn print_history(
+    store: &JSONEventStore,
+    origin_id: &str,
+    args: &cli::HistoryArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = query::HistoryFilter {
+        types: args.types.clone(),
+        since: args.since,
+        until: args.until,
+    };
+    let entries = query::timeline(store, origin_id, &filter)?;
+    let total = entries.len();
+    let page: Vec<&query::HistoryEntry> = entries
+        .iter()
+        .skip(args.offset)
+        .take(args.limit.unwrap_or(usize::MAX))
+        .collect();
+    if page.is_empty() {
+        println!("No matching events");
+    }
+    for entry in page.iter() {
+        println!(
+            "{}  {:>4}  {:<24} {}",
+            entry.time.format("%Y-%m-%d %H:%M:%S"),
+            entry.generation,
+            entry.name,
+            entry.summary
+        );
+    }
+    let end = args.offset + page.len();
+    if !page.is_empty() && (args.offset > 0 || end < total) {
+        let more = match end < total {
+            true => format!(", the next ones with --offset {}", end),
+            false => String::new(),
+        };
+        println!("Events {}-{} of {}{}", args.offset + 1, end, total, more);
+    }
+    Ok(())
+}
+
+fn print_playlist(state: &domain::PlaylistData) {
+    let playlist = &state.data;
+    println!("{} ( {} )", playlist.name, playlist.id);
+    println!("Owner: {}", playlist.owner.name_or_id());
+    if let Some(description) = playlist.description.as_deref().filter(|d| !d.is_empty()) {
+        println!("Description: {}", description);
+    }
+    println!("Generation: {}", state.generation);
+    println!("Tracks: {}", playlist.tracks.len());
+    for (idx, item) in playlist.tracks.iter().enumerate() {
+        println!("{:>4}. {}", idx + 1, item);
+    }
+}
+
+/// `spt diff` in the json output mode
+#[derive(serde::Serialize)]
+struct DiffOutput<'a> {
+    id: &'a str,
+    name: &'a str,
+    /// The requested times, `None` for the first and the current state
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    changes: &'a query::PlaylistDiff,
+}
+
+fn print_diff(state: &domain::PlaylistData, diff: &query::PlaylistDiff, colored: bool) {
+    println!("{} ( {} )", state.data.name, state.data.id);
+    if diff.is_empty() {
+        println!("No changes");
+    }
+    for line in diff_lines(diff, colored) {
+        println!("{}", line);
+    }
+}
+
+/// Added items are green, removed ones red and everything else yellow if `colored`
+fn diff_lines(diff: &query::PlaylistDiff, colored: bool) -> Vec<String> {
+    let paint = |line: String, color: console::Color| match colored {
+        true => console::style(line).fg(color).to_string(),
+        false => line,
+    };
+    let mut lines = Vec::new();
+    if let Some((from, to)) = &diff.name {
+        lines.push(paint(
+            format!("Name: {} -> {}", from, to),
+            console::Color::Yellow,
+        ));
+    }
+    if let Some((from, to)) = &diff.description {
+        lines.push(paint(
+            format!(
+                "Description: {} -> {}",
+                from.as_deref().unwrap_or_default(),
+                to.as_deref().unwrap_or_default()
+            ),
+            console::Color::Yellow,
         ));
+    }
+    lines.extend(
+        diff.added
+            .iter()
+            .map(|item| paint(format!("+ {}", item), console::Color::Green)),
+    );
+    lines.extend(
+        diff.removed
+            .iter()
+            .map(|item| paint(format!("- {}", item), console::Color::Red)),
+    );
+    lines.extend(diff.moved.iter().map(|moved| {
+        let line = format!(
+            "~ {} (#{} -> #{})",
+            moved.item,
+            moved.from + 1,
+            moved.to + 1
+        );
+        paint(line, console::Color::Yellow)
+    }));
+    lines
+}
 
-        pb.inc(1);
+/// What the events of a dry run would change. New playlists are only summed up, all their
+/// tracks would be added.
+fn pending_lines(
+    label: &str,
+    before: &domain::PlaylistData,
+    after: &domain::PlaylistData,
+    events: &[domain::PlaylistEvent],
+    colored: bool,
+) -> Vec<String> {
+    let names: Vec<&str> = events.iter().map(|event| event.name()).collect();
+    let mut lines = vec![format!(
+        "[{}] Would record {} for {} ( {} )",
+        label,
+        names.join(", "),
+        after.data.name,
+        after.data.id
+    )];
+    let changes = match before.generation {
+        0 => vec![format!("Created with {} tracks", after.data.tracks.len())],
+        _ => diff_lines(&query::diff(before, after), colored),
+    };
+    lines.extend(changes.into_iter().map(|line| format!("  {}", line)));
+    for event in events {
+        if let domain::PlaylistEvent::TrackAvailabilityChanged(_, _, items) = event {
+            lines.extend(items.iter().map(|item| {
+                let availability = match item.is_unavailable() {
+                    true => "unavailable",
+                    false => "available",
+                };
+                format!("  ! {} ({})", item, availability)
+            }));
+        }
     }
-    pb.finish_with_message("Finished!");
-    for pb in &pbs {
-        multi.remove(pb);
+    lines
+}
+
+/// Writes the current state of the playlists as JSON
+fn export(
+    store: &JSONEventStore,
+    args: &cli::ExportArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let states = match &args.playlist {
+        Some(playlist) => vec![spt::build_local(
+            &query::find_playlist(store, playlist)?,
+            store,
+        )?],
+        None => query::playlists(store)?,
+    };
+    match &args.output {
+        Some(path) => {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            serde_json::to_writer_pretty(file, &states)?;
+            eprintln!("Exported {} playlists to {}", states.len(), path.display());
+        }
+        None => {
+            serde_json::to_writer_pretty(std::io::stdout().lock(), &states)?;
+            println!();
+        }
     }
-    pb.tick();
+    Ok(())
+}
 
-    // Write to disk
-    let before = Instant::now();
-    match event_store.save_events(&store_path) {
-        Ok(_) => println!(
-            "Saved all events to {} in {:.2?}",
-            store_path,
-            before.elapsed()
-        ),
-        Err(err) => eprintln!("Failed to save events to {}: {}", store_path, err),
+fn verify(store: &JSONEventStore) -> Result<(), Box<dyn std::error::Error>> {
+    let problems = query::verify(store);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(format!("Found {} problems", problems.len()).into());
     }
+    println!(
+        "All {} playlists can be rebuilt from {} events",
+        store.origin_ids().len(),
+        store.len()
+    );
+    Ok(())
+}
+
+fn print_stats(store: &JSONEventStore) -> Result<(), Box<dyn std::error::Error>> {
+    let stats = query::stats(store)?;
+    println!("Playlists: {}", stats.playlists);
+    println!("Tracks:    {}", stats.tracks);
+    println!("Events:    {}", stats.events);
+    for (name, count) in stats.events_by_type.iter() {
+        println!("    {:<24} {}", name, count);
+    }
+    if let (Some(first), Some(last)) = (stats.first_event, stats.last_event) {
+        println!("First event: {}", first.format("%Y-%m-%d %H:%M:%S"));
+        println!("Last event:  {}", last.format("%Y-%m-%d %H:%M:%S"));
+    }
+    Ok(())
+}
+
+/// Prints the currently unavailable tracks of every stored playlist
+fn print_unavailable(event_store: &JSONEventStore) -> Result<(), types::SPTError> {
+    let report = spt::unavailable_tracks(event_store)?;
+    if report.is_empty() {
+        println!("\nAll tracks are available");
+    }
+    for (playlist, tracks) in report {
+        println!(
+            "\n{} ( {} ): {} unavailable",
+            playlist.data.name,
+            playlist.data.id,
+            tracks.len()
+        );
+        for track in tracks.iter() {
+            match track.restrictions() {
+                Some(reason) => println!("    {} [{}]", track, reason),
+                None => println!("    {}", track),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the fetch options, the automatic field selectors can be overridden in the config and
+/// with environment variables. An empty value requests the full objects.
+fn fetch_options(
+    config: &Config,
+    market: Option<&str>,
+) -> Result<types::FetchOptions, types::SPTError> {
+    let mut options = types::FetchOptions::new();
+    let fields =
+        |var: &str, configured: &Option<String>| env::var(var).ok().or_else(|| configured.clone());
+    if let Some(fields) = fields(PLAYLIST_FIELDS_VAR, &config.fetch.playlist_fields) {
+        options.playlist_fields = Some(fields).filter(|fields| !fields.trim().is_empty());
+    }
+    if let Some(fields) = fields(ITEM_FIELDS_VAR, &config.fetch.item_fields) {
+        options.item_fields = Some(fields).filter(|fields| !fields.trim().is_empty());
+    }
+    if let Some(market) = market {
+        options.market = Some(types::parse_market(market)?);
+    }
+    options.check_availability = match env::var(CHECK_AVAILABILITY_VAR) {
+        Ok(value) => !matches!(value.trim(), "" | "0" | "false"),
+        Err(_) => config.fetch.check_availability.unwrap_or(false),
+    };
+    Ok(options)
+}
+
+/// Sends the new events to every sink, a failing sink doesn't stop the others
+fn send_notifications(sinks: &[notify::Sink], events: &[UniqueEvent]) {
+    for sink in sinks {
+        if let Err(err) = sink.send(events) {
+            eprintln!("Failed to notify {}: {}", sink, err);
+        }
+    }
+}
 
+/// Validates the config and checks that the files it points to can be loaded
+fn check_config(
+    profile: &profile::Profile,
+    config: &Config,
+    global: &cli::GlobalArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = match profile.config.exists() {
+        true => config.validate(&profile.env),
+        false => {
+            println!(
+                "No config file at {}, using the defaults",
+                profile.config.display()
+            );
+            vec![]
+        }
+    };
+    match load_targets(profile, config, &[], false) {
+        Ok(targets) => {
+            if let Err(err) = oauth_config(profile, config, &targets.users) {
+                problems.push(format!("credentials: {}", err));
+            }
+        }
+        Err(err) => problems.push(err.to_string()),
+    }
+    // The environment can still break settings that are valid in the file
+    if problems.is_empty() {
+        if let Err(err) = Settings::new(config, global).and_then(|settings| Ok(settings.styles()?))
+        {
+            problems.push(err.to_string());
+        }
+    }
+    if problems.is_empty() {
+        println!("Config of profile {} is valid", profile);
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("  {}", problem);
+    }
+    Err(format!(
+        "Found {} problems in {}",
+        problems.len(),
+        profile.config.display()
+    )
+    .into())
+}
+
+/// Prints the effective config of the profile, with the resolved paths and the settings
+/// overridden by the environment. The client secret and the webhook urls are hidden.
+fn show_config(
+    profile: &profile::Profile,
+    config: &Config,
+    global: &cli::GlobalArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = Settings::new(config, global)?;
+    let mut credentials = match &config.credentials {
+        Some(credentials) => credentials
+            .clone()
+            .merge(login::OAuthConfig::from_env(&profile.env)),
+        None => {
+            login::OAuthConfig::load_from(&profile.credentials, &profile.env).unwrap_or_default()
+        }
+    };
+    if credentials.client_secret.is_some() {
+        credentials.client_secret = Some("<hidden>".to_string());
+    }
+    let effective = Config {
+        paths: config::Paths {
+            data_dir: Some(profile.data_dir.clone()),
+            events: Some(profile.events.clone()),
+            users: Some(profile.users.clone()),
+            playlists: Some(profile.playlists.clone()),
+            filter: Some(profile.filter.clone()),
+            credentials: Some(profile.credentials.clone()),
+            token: Some(profile.token.clone()),
+        },
+        credentials: Some(credentials),
+        fetch: config::Fetch {
+            jobs: Some(settings.jobs),
+            market: settings.market.clone(),
+            check_availability: Some(settings.fetch.check_availability),
+            playlist_fields: settings.fetch.playlist_fields.clone(),
+            item_fields: settings.fetch.item_fields.clone(),
+        },
+        watch: config::Watch {
+            interval: Some(format!("{}s", settings.interval.as_secs())),
+        },
+        progress: config::Progress {
+            main: Some(settings.progress.main().to_string()),
+            lower: Some(settings.progress.lower().to_string()),
+            chars: Some(settings.progress.chars().to_string()),
+        },
+        output: config::Output {
+            mode: Some(settings.output),
+        },
+        notify: config.notify.iter().map(notify::Sink::masked).collect(),
+        ..config.clone()
+    };
+    println!("# Profile {}, config {}", profile, profile.config.display());
+    print!("{}", effective.to_toml()?);
     Ok(())
 }
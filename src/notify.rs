@@ -0,0 +1,142 @@
+//! Notifications
+//!
+//! Sinks configured in the config file receive the events recorded by a run, e.g. to forward
+//! them to a chat or to another program.
+
+use crate::eventsourcing::prelude::UniqueEvent;
+use crate::types::SPTError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Sink {
+    /// Appends every event as a JSON line to a file
+    File { path: PathBuf },
+    /// Runs a command with the events as JSON lines on stdin
+    Command {
+        command: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<String>,
+    },
+    /// Posts the events as a JSON array to a URL
+    Webhook { url: String },
+}
+
+impl Sink {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Sink::File { path } if path.as_os_str().is_empty() => {
+                Err("The file sink needs a path".to_string())
+            }
+            Sink::Command { command, .. } if command.trim().is_empty() => {
+                Err("The command sink needs a command".to_string())
+            }
+            Sink::Webhook { url }
+                if !url.starts_with("http://") && !url.starts_with("https://") =>
+            {
+                Err(format!(
+                    "Invalid webhook url {}, it has to be a http(s) url",
+                    mask_url(url)
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the sink with the secrets hidden, a webhook url keeps only its scheme and host
+    pub fn masked(&self) -> Sink {
+        match self {
+            Sink::Webhook { url } => Sink::Webhook { url: mask_url(url) },
+            sink => sink.clone(),
+        }
+    }
+
+    /// Sends the events to the sink, nothing is sent without events
+    pub fn send(&self, events: &[UniqueEvent]) -> Result<(), SPTError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Sink::File { path } => {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                file.write_all(json_lines(events)?.as_bytes())?;
+            }
+            Sink::Command { command, args } => {
+                let mut child = Command::new(command)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(json_lines(events)?.as_bytes())?;
+                }
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(SPTError::Notify(format!(
+                        "{} exited with {}",
+                        command, status
+                    )));
+                }
+            }
+            Sink::Webhook { url } => {
+                ureq::post(url)
+                    .set("Content-Type", "application/json")
+                    .send_string(&serde_json::to_string(events)?)
+                    // The text of ureq's errors contains the url, which is a secret
+                    .map_err(|err| {
+                        SPTError::Notify(match err {
+                            ureq::Error::Status(code, _) => {
+                                format!("The webhook answered with status {}", code)
+                            }
+                            err => format!("The webhook request failed: {}", err.kind()),
+                        })
+                    })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sink::File { path } => write!(f, "file {}", path.display()),
+            Sink::Command { command, .. } => write!(f, "command {}", command),
+            Sink::Webhook { url } => write!(f, "webhook {}", mask_url(url)),
+        }
+    }
+}
+
+/// Keeps the scheme and the host of a url, the path and the query often hold a token
+fn mask_url(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme), rest),
+        None => (String::new(), url),
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match rest.len() > host.len() {
+        true => format!("{}{}/<hidden>", scheme, host),
+        false => url.to_string(),
+    }
+}
+
+fn json_lines(events: &[UniqueEvent]) -> Result<String, serde_json::Error> {
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(event)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
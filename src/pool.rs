@@ -0,0 +1,63 @@
+//! Bounded Worker Pool
+//!
+//! A tiny scoped thread pool used to fetch users and playlists in parallel without pulling in
+//! an async runtime. Workers borrow from the caller's stack, so shared state like the spotify
+//! client, the event store and the progress bars can be passed by reference.
+
+use std::sync::Mutex;
+use std::thread;
+
+/// Default number of workers if nothing else is configured
+pub const DEFAULT_JOBS: usize = 4;
+
+/// Applies `f` to every item using at most `jobs` worker threads.
+/// The results are returned in the same order as the input items.
+pub fn map<T, R, F>(jobs: usize, items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let len = items.len();
+    let workers = jobs.max(1).min(len);
+    if workers <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..len).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                // Take the lock only for as long as it takes to grab the next item
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((idx, item)) => {
+                        let result = f(item);
+                        results.lock().unwrap()[idx] = Some(result);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every queued item is processed by a worker"))
+        .collect()
+}
+
+/// Parses a concurrency limit, a positive number of workers
+pub fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(jobs) if jobs > 0 => Ok(jobs),
+        _ => Err(format!(
+            "Invalid number of jobs {:?}, expected a positive number",
+            value
+        )),
+    }
+}
@@ -0,0 +1,301 @@
+//! Profiles
+//!
+//! A profile bundles everything that belongs to one Spotify account: the OAuth credentials, the
+//! saved token, the tracked users and playlists and the data directory with the events. Without
+//! a profile the tracker uses `credentials.json` and `data/` in the working directory, named
+//! profiles live in `profiles/<name>/`. The token of a profile is saved in the state directory,
+//! see [`token::profile_path`].
+
+use crate::config::Config;
+use crate::login;
+use crate::token;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PROFILES_DIR: &str = "profiles";
+const CREDENTIALS_VAR: &str = "SPT_CREDENTIALS";
+const CREDENTIALS_FILE: &str = "credentials.json";
+const USER_FILE: &str = "users.json";
+const PLAYLIST_FILE: &str = "playlists.json";
+const FILTER_FILE: &str = "filter.json";
+const CONFIG_FILE: &str = "spt.toml";
+const DATA_DIR: &str = "data";
+const EVENTS_FILE: &str = "events.json";
+/// Profile name that selects every profile
+const ALL_PROFILES: &str = "all";
+
+#[derive(Debug)]
+pub enum ProfileError {
+    /// Only letters, digits, `-` and `_` are allowed
+    InvalidName(String),
+    /// The directory of the profile doesn't exist
+    Unknown(String, PathBuf),
+    /// `profiles/` is missing or has no usable profile
+    NoProfiles,
+    IO(std::io::Error),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProfileError::InvalidName(name) => write!(
+                f,
+                "Invalid profile name {}, only letters, digits, - and _ are allowed",
+                name
+            ),
+            ProfileError::Unknown(name, dir) => write!(
+                f,
+                "Unknown profile {}, create the directory {}",
+                name,
+                dir.display()
+            ),
+            ProfileError::NoProfiles => write!(
+                f,
+                "No profiles found, create them in {}/<name>",
+                PROFILES_DIR
+            ),
+            ProfileError::IO(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ProfileError {}
+
+impl From<std::io::Error> for ProfileError {
+    fn from(err: std::io::Error) -> Self {
+        ProfileError::IO(err)
+    }
+}
+
+/// Which profiles to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// The files in the working directory
+    Default,
+    Named(String),
+    /// Every profile in `profiles/`, one after another
+    All,
+}
+
+impl Selection {
+    /// Selection of the `--profile` and `--all-profiles` options
+    pub fn new(profile: Option<&str>, all_profiles: bool) -> Selection {
+        match profile.map(str::trim) {
+            _ if all_profiles => Selection::All,
+            Some(name) if !name.is_empty() => Selection::from_name(name),
+            _ => Selection::Default,
+        }
+    }
+
+    fn from_name(name: &str) -> Selection {
+        match name {
+            ALL_PROFILES => Selection::All,
+            name => Selection::Named(name.to_string()),
+        }
+    }
+
+    /// Resolves the selection to the profiles to run
+    pub fn profiles(&self) -> Result<Vec<Profile>, ProfileError> {
+        match self {
+            Selection::Default => Ok(vec![Profile::default()]),
+            Selection::Named(name) => Ok(vec![Profile::named(name)?]),
+            // The plain credential variables would log every profile in as the same account
+            Selection::All => Ok(Profile::all()?
+                .into_iter()
+                .map(|mut profile| {
+                    profile.env = login::EnvScope::new(profile.name.as_deref(), false);
+                    profile
+                })
+                .collect()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// `None` for the default profile
+    pub name: Option<String>,
+    /// Config file, it doesn't have to exist
+    pub config: PathBuf,
+    pub credentials: PathBuf,
+    pub token: PathBuf,
+    pub users: PathBuf,
+    /// Playlists tracked on their own
+    pub playlists: PathBuf,
+    /// Global playlist filter
+    pub filter: PathBuf,
+    pub events: PathBuf,
+    pub data_dir: PathBuf,
+    /// Where the credential variables are read from
+    pub env: login::EnvScope,
+}
+
+impl Profile {
+    /// Profile of the working directory, the credentials and token file can be moved with
+    /// `SPT_CREDENTIALS` and `SPT_TOKEN_FILE`
+    pub fn new() -> Profile {
+        let data_dir = PathBuf::from(DATA_DIR);
+        Profile {
+            name: None,
+            config: PathBuf::from(CONFIG_FILE),
+            credentials: env::var(CREDENTIALS_VAR)
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from(CREDENTIALS_FILE)),
+            token: login::token_path(),
+            users: data_dir.join(USER_FILE),
+            playlists: data_dir.join(PLAYLIST_FILE),
+            filter: data_dir.join(FILTER_FILE),
+            events: data_dir.join(EVENTS_FILE),
+            data_dir,
+            env: login::EnvScope::default(),
+        }
+    }
+
+    /// Profile stored in `profiles/<name>/`
+    pub fn named(name: &str) -> Result<Profile, ProfileError> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ProfileError::InvalidName(name.to_string()));
+        }
+        let dir = PathBuf::from(PROFILES_DIR).join(name);
+        if !dir.is_dir() {
+            return Err(ProfileError::Unknown(name.to_string(), dir));
+        }
+        Ok(Profile {
+            name: Some(name.to_string()),
+            config: dir.join(CONFIG_FILE),
+            credentials: dir.join(CREDENTIALS_FILE),
+            token: token::profile_path(name),
+            users: dir.join(USER_FILE),
+            playlists: dir.join(PLAYLIST_FILE),
+            filter: dir.join(FILTER_FILE),
+            events: dir.join(DATA_DIR).join(EVENTS_FILE),
+            data_dir: dir.join(DATA_DIR),
+            env: login::EnvScope::new(Some(name), true),
+        })
+    }
+
+    /// Every profile in `profiles/`, sorted by name. Directories that aren't valid profiles
+    /// are skipped with a warning.
+    pub fn all() -> Result<Vec<Profile>, ProfileError> {
+        let entries = match fs::read_dir(PROFILES_DIR) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ProfileError::NoProfiles)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        names.sort();
+        let profiles: Vec<Profile> = names
+            .iter()
+            .filter_map(|name| match Profile::named(name) {
+                Ok(profile) => Some(profile),
+                Err(err) => {
+                    eprintln!("Skipping {}/{}: {}", PROFILES_DIR, name, err);
+                    None
+                }
+            })
+            .collect();
+        match profiles.is_empty() {
+            true => Err(ProfileError::NoProfiles),
+            false => Ok(profiles),
+        }
+    }
+
+    /// Moves the data directory (and the files stored in it) and the credentials
+    pub fn with_overrides(
+        mut self,
+        data_dir: Option<&Path>,
+        credentials: Option<&Path>,
+    ) -> Profile {
+        if let Some(data_dir) = data_dir {
+            self.move_data_dir(data_dir);
+        }
+        if let Some(credentials) = credentials {
+            self.credentials = credentials.to_path_buf();
+        }
+        self
+    }
+
+    /// Uses the paths of the config file, relative ones are resolved against the directory
+    /// of the config file. The credentials and token file set in the environment are kept,
+    /// they only apply to the default profile.
+    pub fn with_config(mut self, config: &Config) -> Profile {
+        let base = self
+            .config
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let resolve = |path: &PathBuf| base.join(path);
+        let paths = &config.paths;
+        if let Some(data_dir) = &paths.data_dir {
+            self.move_data_dir(&resolve(data_dir));
+        }
+        for (file, path) in [
+            (&mut self.events, &paths.events),
+            (&mut self.users, &paths.users),
+            (&mut self.playlists, &paths.playlists),
+            (&mut self.filter, &paths.filter),
+        ] {
+            if let Some(path) = path {
+                *file = resolve(path);
+            }
+        }
+        if let Some(credentials) = paths
+            .credentials
+            .as_ref()
+            .filter(|_| self.name.is_some() || env::var(CREDENTIALS_VAR).is_err())
+        {
+            self.credentials = resolve(credentials);
+        }
+        if let Some(token) = paths
+            .token
+            .as_ref()
+            .filter(|_| self.name.is_some() || login::token_path_override().is_none())
+        {
+            self.token = resolve(token);
+        }
+        self
+    }
+
+    /// Moves the data directory together with the files stored in it
+    fn move_data_dir(&mut self, data_dir: &Path) {
+        for file in [
+            &mut self.users,
+            &mut self.playlists,
+            &mut self.filter,
+            &mut self.events,
+        ] {
+            if let Ok(relative) = file.strip_prefix(&self.data_dir) {
+                *file = data_dir.join(relative);
+            }
+        }
+        self.data_dir = data_dir.to_path_buf();
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "default"),
+        }
+    }
+}
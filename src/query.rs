@@ -0,0 +1,414 @@
+//! Queries
+//!
+//! Read-only views on the recorded events, used by the `history`, `show`, `diff`, `export`,
+//! `verify` and `stats` commands.
+
+use crate::build_local;
+use crate::eventsourcing::domain::{PlaylistAggregate, PlaylistData, PlaylistEvent};
+use crate::eventsourcing::eventstore::JSONEventStore;
+use crate::eventsourcing::prelude::*;
+use crate::schedule;
+use crate::types::{self, SPTError};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use rspotify::model::PlaylistId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const PLAYLIST_URI: &str = "spotify:playlist:";
+const PLAYLIST_LINK: &str = "open.spotify.com/playlist/";
+
+/// Turns a playlist id, URI or open.spotify.com link into the playlist URI
+pub fn playlist_uri(query: &str) -> Option<String> {
+    let query = query.trim();
+    let id = match query.find(PLAYLIST_LINK) {
+        Some(idx) => {
+            let id = &query[idx + PLAYLIST_LINK.len()..];
+            id.split(['?', '/']).next().unwrap_or(id)
+        }
+        None => query,
+    };
+    PlaylistId::from_id_or_uri(id).map(|id| id.to_string()).ok()
+}
+
+/// Current state of every playlist in the store
+pub fn playlists(store: &JSONEventStore) -> Result<Vec<PlaylistData>, SPTError> {
+    store
+        .origin_ids()
+        .iter()
+        .map(|id| Ok(build_local(id, store)?))
+        .collect()
+}
+
+/// Finds the stream of a playlist by its id, URI, open.spotify.com link or current name
+pub fn find_playlist(store: &JSONEventStore, query: &str) -> Result<String, SPTError> {
+    let query = query.trim();
+    let ids = store.origin_ids();
+
+    let id = match query.starts_with(PLAYLIST_URI) || query.contains(PLAYLIST_LINK) {
+        true => playlist_uri(query),
+        false => playlist_uri(query).filter(|id| ids.contains(id)),
+    };
+    if let Some(id) = id {
+        return match ids.contains(&id) {
+            true => Ok(id),
+            false => Err(SPTError::Lookup(format!("Playlist {} isn't tracked", id))),
+        };
+    }
+
+    let matches: Vec<PlaylistData> = playlists(store)?
+        .into_iter()
+        .filter(|state| state.data.name.eq_ignore_ascii_case(query))
+        .collect();
+    match matches.as_slice() {
+        [state] => Ok(state.data.id.clone()),
+        [] => Err(SPTError::Lookup(format!("No playlist named {}", query))),
+        _ => Err(SPTError::Lookup(format!(
+            "{} playlists are named {}, use one of the ids: {}",
+            matches.len(),
+            query,
+            matches
+                .iter()
+                .map(|state| state.data.id.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Parses a point in time: an RFC 3339 timestamp, a date (midnight) or a date and time, both in
+/// UTC like the times `spt history` prints, or a duration like `30d` that has passed since then
+pub fn parse_time(input: &str) -> Result<DateTime<Utc>, String> {
+    let input = input.trim();
+    if input == "now" {
+        return Ok(Utc::now());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(input) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(input, format) {
+            return Ok(DateTime::from_utc(time, Utc));
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_time(NaiveTime::MIN), Utc));
+    }
+    // A plain number is more likely a mistyped date than seconds
+    let invalid = || {
+        format!(
+            "Invalid time {}, expected e.g. 2024-05-01, \"2024-05-01 18:00\", 2024-05-01T18:00:00Z or 30d",
+            input
+        )
+    };
+    if !input.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(invalid());
+    }
+    let ago = schedule::parse_interval(input).map_err(|_| invalid())?;
+    let ago = chrono::Duration::from_std(ago).map_err(|err| err.to_string())?;
+    Utc::now()
+        .checked_sub_signed(ago)
+        .ok_or_else(|| format!("{} is too long ago", input))
+}
+
+/// Events of a playlist together with the time they were recorded
+pub fn history(
+    store: &JSONEventStore,
+    origin_id: &str,
+) -> Result<Vec<(DateTime<Utc>, PlaylistEvent)>, SPTError> {
+    store
+        .get_events(origin_id.to_string())
+        .into_iter()
+        .map(|event| {
+            let time = event.event_time;
+            let event: Result<PlaylistEvent, serde_json::Error> = event.into();
+            Ok((time, event?))
+        })
+        .collect()
+}
+
+/// An event of a playlist as `spt history` lists it
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub time: DateTime<Utc>,
+    /// Generation of the playlist after the event
+    pub generation: u64,
+    pub name: &'static str,
+    pub summary: String,
+    pub event: PlaylistEvent,
+}
+
+/// Which events of a playlist are listed, every event by default
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    /// Event names as returned by [`parse_event_type`], any event if empty
+    pub types: Vec<&'static str>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, time: DateTime<Utc>, event: &PlaylistEvent) -> bool {
+        (self.types.is_empty() || self.types.contains(&event.name()))
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+    }
+}
+
+/// Event name from the command line, case and `-`/`_` are ignored and a unique prefix is
+/// enough, e.g. `added` for `AddedTracks`
+pub fn parse_event_type(input: &str) -> Result<&'static str, String> {
+    let normalize = |name: &str| name.replace(['-', '_'], "").to_lowercase();
+    let input = normalize(input);
+    if let Some(name) = PlaylistEvent::NAMES
+        .into_iter()
+        .find(|name| normalize(name) == input)
+    {
+        return Ok(name);
+    }
+    let matches: Vec<&'static str> = PlaylistEvent::NAMES
+        .into_iter()
+        .filter(|name| !input.is_empty() && normalize(name).starts_with(&input))
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(name),
+        [] => Err(format!(
+            "Unknown event type, use one of {}",
+            PlaylistEvent::NAMES.join(", ")
+        )),
+        names => Err(format!("Ambiguous event type: {}", names.join(", "))),
+    }
+}
+
+/// Events of a playlist that pass the `filter`, with a summary of what they changed
+pub fn timeline(
+    store: &JSONEventStore,
+    origin_id: &str,
+    filter: &HistoryFilter,
+) -> Result<Vec<HistoryEntry>, SPTError> {
+    let mut state = PlaylistData::new();
+    let mut entries = Vec::new();
+    for (time, event) in history(store, origin_id)? {
+        let summary = summary(&state, &event);
+        // Deleted playlists aren't recorded, their state can't be built
+        if !matches!(event, PlaylistEvent::DeletedPlaylist(_)) {
+            state = PlaylistAggregate::apply_event(state, &event)?;
+        }
+        if filter.matches(time, &event) {
+            entries.push(HistoryEntry {
+                time,
+                generation: state.generation,
+                name: event.name(),
+                summary,
+                event,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Items named in a summary before the rest is only counted
+const SUMMARY_ITEMS: usize = 5;
+
+/// Human readable summary of an event, `before` is the state the event was applied to
+pub fn summary(before: &PlaylistData, event: &PlaylistEvent) -> String {
+    fn items<T: std::fmt::Display>(items: impl ExactSizeIterator<Item = T>) -> String {
+        let len = items.len();
+        let mut names: Vec<String> = items.take(SUMMARY_ITEMS).map(|i| i.to_string()).collect();
+        if len > SUMMARY_ITEMS {
+            names.push(format!("and {} more", len - SUMMARY_ITEMS));
+        }
+        names.join(", ")
+    }
+    let description = |description: &Option<String>| match description.as_deref() {
+        Some(description) if !description.is_empty() => format!("\"{}\"", description),
+        _ => "none".to_string(),
+    };
+
+    match event {
+        PlaylistEvent::CreatedPlaylist(_, playlist) => {
+            format!(
+                "created {} with {} tracks",
+                playlist.name,
+                playlist.tracks.len()
+            )
+        }
+        PlaylistEvent::UpdatedName(_, name) => {
+            format!("renamed {} → {}", before.data.name, name)
+        }
+        PlaylistEvent::UpdatedDesciption(_, new) => format!(
+            "description {} → {}",
+            description(&before.data.description),
+            description(new)
+        ),
+        PlaylistEvent::AddedTracks(_, _, tracks) => {
+            format!("+{} tracks: {}", tracks.len(), items(tracks.iter()))
+        }
+        PlaylistEvent::RemovedTracks(_, _, tracks) => {
+            format!("-{} tracks: {}", tracks.len(), items(tracks.iter()))
+        }
+        PlaylistEvent::TrackAvailabilityChanged(_, _, tracks) => {
+            let availability = tracks.iter().map(|item| match item.is_unavailable() {
+                true => format!("{} (unavailable)", item),
+                false => format!("{} (available)", item),
+            });
+            format!("~{} tracks: {}", tracks.len(), items(availability))
+        }
+        PlaylistEvent::DeletedPlaylist(_) => "deleted".to_string(),
+    }
+}
+
+/// Changes between two states of a playlist
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct PlaylistDiff {
+    pub name: Option<(String, String)>,
+    pub description: Option<(Option<String>, Option<String>)>,
+    pub added: types::PlaylistItems,
+    pub removed: types::PlaylistItems,
+    /// Items in both states at another position, not counting the shifts caused by added and
+    /// removed items. Reorders aren't recorded as events, so this only reflects the stored
+    /// order, see the README.
+    pub moved: Vec<types::MovedItem>,
+}
+
+impl PlaylistDiff {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.description.is_none()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+/// Compares two states of a playlist, items are matched by their identity
+pub fn diff(from: &PlaylistData, to: &PlaylistData) -> PlaylistDiff {
+    let before: HashSet<types::ItemKey> = from.data.tracks.iter().map(|item| item.key()).collect();
+    let after: HashSet<types::ItemKey> = to.data.tracks.iter().map(|item| item.key()).collect();
+    PlaylistDiff {
+        name: Some((from.data.name.clone(), to.data.name.clone())).filter(|(a, b)| a != b),
+        description: Some((from.data.description.clone(), to.data.description.clone()))
+            .filter(|(a, b)| a != b),
+        added: to
+            .data
+            .tracks
+            .iter()
+            .filter(|item| !before.contains(&item.key()))
+            .cloned()
+            .collect(),
+        removed: from
+            .data
+            .tracks
+            .iter()
+            .filter(|item| !after.contains(&item.key()))
+            .cloned()
+            .collect(),
+        moved: from.data.tracks.moves_to(&to.data.tracks),
+    }
+}
+
+/// State of a playlist right after it was first recorded
+pub fn first_state(store: &JSONEventStore, origin_id: &str) -> Result<PlaylistData, SPTError> {
+    let events: Vec<PlaylistEvent> = store.get_all(origin_id.to_string());
+    Ok(PlaylistAggregate::apply_all(
+        PlaylistData::new(),
+        &events[..events.len().min(1)],
+    )?)
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub events: usize,
+    pub playlists: usize,
+    /// Items in the current state of all playlists
+    pub tracks: usize,
+    pub events_by_type: BTreeMap<&'static str, usize>,
+    pub first_event: Option<DateTime<Utc>>,
+    pub last_event: Option<DateTime<Utc>>,
+}
+
+pub fn stats(store: &JSONEventStore) -> Result<Stats, SPTError> {
+    let mut stats = Stats {
+        playlists: store.origin_ids().len(),
+        tracks: playlists(store)?
+            .iter()
+            .map(|state| state.data.tracks.len())
+            .sum(),
+        ..Default::default()
+    };
+    for event in store.evts.lock().unwrap().iter() {
+        let time = event.event_time;
+        let event: PlaylistEvent = serde_json::from_value(event.data.clone())?;
+        stats.events += 1;
+        *stats.events_by_type.entry(event.name()).or_default() += 1;
+        stats.first_event = Some(stats.first_event.map_or(time, |first| first.min(time)));
+        stats.last_event = Some(stats.last_event.map_or(time, |last| last.max(time)));
+    }
+    Ok(stats)
+}
+
+/// Checks that every stream can be rebuilt and returns the problems found
+pub fn verify(store: &JSONEventStore) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut streams: HashMap<String, Vec<PlaylistEvent>> = HashMap::new();
+    let mut last_time: HashMap<String, DateTime<Utc>> = HashMap::new();
+
+    for event in store.evts.lock().unwrap().iter() {
+        let origin_id = &event.origin_id;
+        match serde_json::from_value::<PlaylistEvent>(event.data.clone()) {
+            Ok(parsed) => {
+                if parsed.event_origin_id() != *origin_id {
+                    problems.push(format!(
+                        "Event {} is stored for {} but belongs to {}",
+                        event.event_id,
+                        origin_id,
+                        parsed.event_origin_id()
+                    ));
+                }
+                streams.entry(origin_id.clone()).or_default().push(parsed);
+            }
+            Err(err) => problems.push(format!(
+                "Event {} of {} can't be parsed: {}",
+                event.event_id, origin_id, err
+            )),
+        }
+        if let Some(last) = last_time.insert(origin_id.clone(), event.event_time) {
+            if last > event.event_time {
+                problems.push(format!(
+                    "Event {} of {} is older than the event before it",
+                    event.event_id, origin_id
+                ));
+            }
+        }
+    }
+
+    for origin_id in store.origin_ids() {
+        let events = streams.remove(&origin_id).unwrap_or_default();
+        match events.first() {
+            Some(PlaylistEvent::CreatedPlaylist(..)) | None => (),
+            Some(event) => problems.push(format!(
+                "{} starts with {} instead of CreatedPlaylist",
+                origin_id,
+                event.name()
+            )),
+        }
+        if events
+            .iter()
+            .any(|event| matches!(event, PlaylistEvent::DeletedPlaylist(_)))
+        {
+            problems.push(format!(
+                "{} contains a DeletedPlaylist event which can't be applied yet",
+                origin_id
+            ));
+            continue;
+        }
+        if let Err(err) = PlaylistAggregate::apply_all(PlaylistData::new(), &events) {
+            problems.push(format!("{} can't be rebuilt: {}", origin_id, err));
+        }
+    }
+    problems
+}
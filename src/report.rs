@@ -0,0 +1,146 @@
+//! Run Reports
+//!
+//! Outcome of a run for every user and playlist. In the `json` output mode the report is
+//! printed when the run is done, a run with failed users or playlists exits with
+//! [`EXIT_PARTIAL`].
+
+use crate::eventsourcing::domain::PlaylistEvent;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+/// Exit code of a run in which some users or playlists failed
+pub const EXIT_PARTIAL: i32 = 2;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RunReport {
+    pub profile: String,
+    /// Nothing was saved, the events of the playlists are only pending
+    pub dry_run: bool,
+    pub started: DateTime<Utc>,
+    pub duration_ms: u128,
+    /// Time spent fetching the playlists of the users and the tracked playlists
+    pub fetch_ms: u128,
+    /// Time spent comparing and updating the playlists
+    pub compare_ms: u128,
+    pub users: Vec<UserOutcome>,
+    pub playlists: Vec<PlaylistOutcome>,
+    /// Number of recorded events by type
+    pub events: BTreeMap<String, usize>,
+    /// Errors that aren't tied to a user or playlist
+    pub errors: Vec<String>,
+    /// Number of failed users and playlists
+    pub failed: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UserOutcome {
+    pub id: String,
+    pub name: String,
+    /// Playlists that passed the filters
+    pub playlists: usize,
+    /// Playlists rejected by the filters
+    pub skipped: usize,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaylistOutcome {
+    pub id: String,
+    pub name: String,
+    /// The user the playlist was fetched for, or its owner
+    pub owner: String,
+    pub status: Status,
+    /// Number of recorded events by type
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub events: BTreeMap<String, usize>,
+    /// The events a dry run would have recorded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<PlaylistEvent>,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Created,
+    Changed,
+    Unchanged,
+    /// Rejected by the global filter
+    Filtered,
+    Failed,
+}
+
+impl RunReport {
+    pub fn new(profile: &str) -> RunReport {
+        RunReport {
+            profile: profile.to_string(),
+            dry_run: false,
+            started: Utc::now(),
+            duration_ms: 0,
+            fetch_ms: 0,
+            compare_ms: 0,
+            users: vec![],
+            playlists: vec![],
+            events: BTreeMap::new(),
+            errors: vec![],
+            failed: 0,
+        }
+    }
+
+    /// Sums up the events and failures once the run is done
+    pub fn finish(&mut self, duration: Duration) {
+        self.duration_ms = duration.as_millis();
+        self.events.clear();
+        for (name, count) in self.playlists.iter().flat_map(|pl| pl.events.iter()) {
+            *self.events.entry(name.clone()).or_default() += count;
+        }
+        self.failed = self
+            .users
+            .iter()
+            .filter(|user| user.error.is_some())
+            .count()
+            + self
+                .playlists
+                .iter()
+                .filter(|pl| pl.status == Status::Failed)
+                .count();
+    }
+
+    /// Error for a run with failures, `Ok` if everything went through
+    pub fn result(&self) -> Result<(), PartialFailure> {
+        match self.failed {
+            0 => Ok(()),
+            failed => Err(PartialFailure {
+                failed,
+                total: self.users.len() + self.playlists.len(),
+            }),
+        }
+    }
+}
+
+/// Some users or playlists of a run failed, the others were recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialFailure {
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} users and playlists failed",
+            self.failed, self.total
+        )
+    }
+}
+
+impl error::Error for PartialFailure {}
@@ -0,0 +1,84 @@
+//! Schedule
+//!
+//! Keeps track of when the users and playlists of `spt watch` are due again. Every target has
+//! its own interval, targets that are due at the same time are fetched in the same run.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Parses an interval like `90`, `90s`, `15m`, `2h` or `1d`, plain numbers are seconds
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let interval = interval.trim();
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let (value, unit) = interval.split_at(split);
+    let invalid = || {
+        format!(
+            "Invalid interval {}, expected e.g. 30s, 15m or 2h",
+            interval
+        )
+    };
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let seconds = match unit.trim() {
+        "" | "s" => Some(value),
+        "m" => value.checked_mul(60),
+        "h" => value.checked_mul(60 * 60),
+        "d" => value.checked_mul(60 * 60 * 24),
+        unit => return Err(format!("Unknown unit {} in interval {}", unit, interval)),
+    };
+    // The schedule adds the interval to the current time, that has to fit as well
+    let seconds = seconds
+        .filter(|seconds| {
+            Instant::now()
+                .checked_add(Duration::from_secs(*seconds))
+                .is_some()
+        })
+        .ok_or_else(invalid)?;
+    if seconds == 0 {
+        return Err("The interval must be longer than 0 seconds".to_string());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// When each target is due next
+#[derive(Debug, Clone)]
+pub struct Schedule<K> {
+    next: HashMap<K, Instant>,
+}
+
+impl<K: Eq + Hash + Clone> Schedule<K> {
+    pub fn new() -> Schedule<K> {
+        Schedule {
+            next: HashMap::new(),
+        }
+    }
+
+    /// Returns the targets that are due at `now` and schedules them again after their
+    /// interval. New targets are due immediately, targets that aren't listed are forgotten.
+    pub fn due(&mut self, targets: &[(K, Duration)], now: Instant) -> Vec<K> {
+        self.next
+            .retain(|key, _| targets.iter().any(|(target, _)| target == key));
+        let mut due = Vec::new();
+        for (key, interval) in targets {
+            let next = self.next.entry(key.clone()).or_insert(now);
+            if *next <= now {
+                *next = now + *interval;
+                due.push(key.clone());
+            }
+        }
+        due
+    }
+
+    /// The earliest time a target is due, `None` without targets
+    pub fn next_due(&self) -> Option<Instant> {
+        self.next.values().min().copied()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for Schedule<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
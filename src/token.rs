@@ -0,0 +1,202 @@
+//! Token Storage
+//!
+//! The OAuth token is kept in the XDG state directory (`~/.local/state/spt/token.json`, or the
+//! config directory on platforms without one) and is only readable by the current user. If a
+//! passphrase is given the token is encrypted with ChaCha20-Poly1305 and a key derived from the
+//! passphrase with PBKDF2.
+
+use crate::login::AuthenticationError;
+use chacha20poly1305::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "spt";
+const PROFILES_DIR: &str = "profiles";
+const TOKEN_FILE: &str = "token.json";
+/// Where tokens were saved before they moved to the state directory
+pub const LEGACY_TOKEN_PATH: &str = "token.tmp";
+const KDF: &str = "pbkdf2-sha256";
+const KDF_ROUNDS: u32 = 600_000;
+/// Rounds accepted from a token file, so a corrupted file can neither hang the start nor
+/// weaken the key derivation
+const KDF_ROUNDS_RANGE: std::ops::RangeInclusive<u32> = 100_000..=10_000_000;
+const SALT_LEN: usize = 16;
+
+/// Layout of an encrypted token file
+#[derive(Serialize, Deserialize, Debug)]
+struct EncryptedToken {
+    kdf: String,
+    rounds: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Default location of the saved token, falls back to the working directory if the platform
+/// has neither a state nor a config directory
+pub fn default_path() -> PathBuf {
+    app_dir().join(TOKEN_FILE)
+}
+
+/// Location of the saved token of a named profile
+pub fn profile_path(name: &str) -> PathBuf {
+    app_dir().join(PROFILES_DIR).join(name).join(TOKEN_FILE)
+}
+
+fn app_dir() -> PathBuf {
+    match dirs::state_dir().or_else(dirs::config_dir) {
+        Some(dir) => dir.join(APP_DIR),
+        None => PathBuf::new(),
+    }
+}
+
+/// Reads a saved token, encrypted tokens need the passphrase they were saved with
+pub fn load(path: &Path, passphrase: Option<&str>) -> Result<rspotify::Token, AuthenticationError> {
+    decode(&fs::read_to_string(path)?, passphrase)
+}
+
+/// Parses the content of a token file
+pub fn decode(
+    content: &str,
+    passphrase: Option<&str>,
+) -> Result<rspotify::Token, AuthenticationError> {
+    match serde_json::from_str::<EncryptedToken>(content) {
+        Ok(encrypted) => match passphrase {
+            Some(passphrase) => decrypt(&encrypted, passphrase),
+            None => Err(AuthenticationError::TokenError(
+                "The saved token is encrypted but no passphrase is configured".to_string(),
+            )),
+        },
+        Err(_) => Ok(serde_json::from_str(content)?),
+    }
+}
+
+/// Saves a token readable only by the current user, encrypted if a passphrase is given.
+/// The file is replaced atomically so a crash never leaves a truncated token behind.
+pub fn save(
+    token: &rspotify::Token,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(), AuthenticationError> {
+    let content = match passphrase {
+        Some(passphrase) => serde_json::to_string(&encrypt(token, passphrase)?)?,
+        None => serde_json::to_string(token)?,
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        create_private_dir(dir)?;
+    }
+    let tmp = path.with_extension("tmp~");
+    let mut file = private_file(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Moves a token from the old location in the working directory to `path`.
+/// Returns `true` if a token was migrated.
+pub fn migrate(
+    legacy: &Path,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<bool, AuthenticationError> {
+    if !legacy.exists() || path.exists() || legacy == path {
+        return Ok(false);
+    }
+    let token = load(legacy, None)?;
+    save(&token, path, passphrase)?;
+    fs::remove_file(legacy)?;
+    Ok(true)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> Key {
+    let mut key = Key::default();
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+fn encrypt(
+    token: &rspotify::Token,
+    passphrase: &str,
+) -> Result<EncryptedToken, AuthenticationError> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, KDF_ROUNDS));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, serde_json::to_vec(token)?.as_slice())
+        .map_err(|_| AuthenticationError::TokenError("Failed to encrypt the token".to_string()))?;
+    Ok(EncryptedToken {
+        kdf: KDF.to_string(),
+        rounds: KDF_ROUNDS,
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(ciphertext),
+    })
+}
+
+fn decrypt(
+    encrypted: &EncryptedToken,
+    passphrase: &str,
+) -> Result<rspotify::Token, AuthenticationError> {
+    let invalid = || AuthenticationError::TokenError("The saved token is corrupted".to_string());
+    if encrypted.kdf != KDF {
+        return Err(AuthenticationError::TokenError(format!(
+            "Unknown key derivation {} of the saved token",
+            encrypted.kdf
+        )));
+    }
+    let salt = base64::decode(&encrypted.salt).map_err(|_| invalid())?;
+    let nonce = base64::decode(&encrypted.nonce).map_err(|_| invalid())?;
+    let ciphertext = base64::decode(&encrypted.ciphertext).map_err(|_| invalid())?;
+    if nonce.len() != 12 || !KDF_ROUNDS_RANGE.contains(&encrypted.rounds) {
+        return Err(invalid());
+    }
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, encrypted.rounds));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| {
+            AuthenticationError::TokenError(
+                "Failed to decrypt the saved token, is the passphrase correct?".to_string(),
+            )
+        })?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+#[cfg(unix)]
+fn private_file(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &Path) -> std::io::Result<fs::File> {
+    fs::File::create(path)
+}
@@ -1,6 +1,8 @@
+use crate::filter::PlaylistFilter;
 use chrono::prelude::{DateTime, Utc};
 use rspotify::{model, prelude::BaseClient, ClientResult};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug)]
 pub enum SPTError {
@@ -9,6 +11,27 @@ pub enum SPTError {
     Client(rspotify::ClientError),
     IO(std::io::Error),
     EventSourcing(crate::eventsourcing::Error),
+    /// A playlist or user given on the command line can't be found unambiguously
+    Lookup(String),
+    /// Invalid regular expression in a playlist filter
+    Regex(regex::Error),
+    /// The config file can't be read or is invalid
+    Config(String),
+    /// A notification couldn't be sent
+    Notify(String),
+}
+
+impl SPTError {
+    /// Spotify answered that the requested object doesn't exist
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            SPTError::Client(rspotify::ClientError::Http(err)) => matches!(
+                &**err,
+                rspotify::http::HttpError::StatusCode(response) if response.status() == 404
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl std::fmt::Display for SPTError {
@@ -19,6 +42,10 @@ impl std::fmt::Display for SPTError {
             SPTError::Client(err) => write!(f, "{}", err),
             SPTError::Authentication(err) => write!(f, "{}", err),
             SPTError::EventSourcing(err) => write!(f, "{}", err),
+            SPTError::Lookup(err) => write!(f, "{}", err),
+            SPTError::Regex(err) => write!(f, "{}", err),
+            SPTError::Config(err) => write!(f, "{}", err),
+            SPTError::Notify(err) => write!(f, "{}", err),
         }
     }
 }
@@ -53,8 +80,85 @@ impl From<crate::eventsourcing::Error> for SPTError {
     }
 }
 
+impl From<regex::Error> for SPTError {
+    fn from(err: regex::Error) -> Self {
+        SPTError::Regex(err)
+    }
+}
+
 impl std::error::Error for SPTError {}
 
+/// Spotify Web API field selector for a type.
+///
+/// The selector lists the fields the `From<model::...>` conversion of the type reads, plus the
+/// fields rspotify needs to deserialize the model at all. Everything else (e.g. the rather large
+/// `available_markets` lists) is left out of the response.
+pub trait Fields {
+    fn fields() -> String;
+}
+
+/// Merges several top-level field selectors into one, dropping duplicate entries
+pub fn merge_fields(selectors: &[&str]) -> String {
+    let mut merged: Vec<&str> = Vec::new();
+    for selector in selectors {
+        let mut depth = 0;
+        let mut start = 0;
+        for (idx, c) in selector.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    merged.push(&selector[start..idx]);
+                    start = idx + 1;
+                }
+                _ => (),
+            }
+        }
+        merged.push(&selector[start..]);
+    }
+    let mut seen = std::collections::HashSet::new();
+    merged.retain(|field| !field.is_empty() && seen.insert(*field));
+    merged.join(",")
+}
+
+/// Options used when fetching playlists from the Spotify Web API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchOptions {
+    /// Field selector for the playlist endpoint, `None` requests the full object
+    pub playlist_fields: Option<String>,
+    /// Field selector for the playlist items endpoint, `None` requests the full objects
+    pub item_fields: Option<String>,
+    pub market: Option<model::Market>,
+    /// Refetch the items of unchanged playlists, availability changes don't touch the snapshot
+    pub check_availability: bool,
+}
+impl FetchOptions {
+    /// Fetch options with the selectors built from [`Playlist`] and [`PlaylistItems`]
+    pub fn new() -> FetchOptions {
+        FetchOptions {
+            playlist_fields: Some(Playlist::fields()),
+            item_fields: Some(PlaylistItems::fields()),
+            market: None,
+            check_availability: false,
+        }
+    }
+}
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses a market from an ISO 3166-1 alpha-2 country code or `from_token`
+pub fn parse_market(market: &str) -> Result<model::Market, SPTError> {
+    let market = market.trim();
+    if market.eq_ignore_ascii_case("from_token") {
+        return Ok(model::Market::FromToken);
+    }
+    let country = serde_json::Value::String(market.to_uppercase());
+    Ok(model::Market::Country(serde_json::from_value(country)?))
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct User {
     pub display_name: Option<String>,
@@ -72,6 +176,11 @@ impl User {
         self.display_name.as_ref().unwrap_or(&self.id)
     }
 }
+impl Default for User {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl From<model::PublicUser> for User {
     fn from(item: model::PublicUser) -> Self {
         User {
@@ -89,89 +198,551 @@ impl From<model::PrivateUser> for User {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+/// A user whose playlists are tracked, an entry of the users file or the `[[users]]` of the
+/// config
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct TrackedUser {
+    /// Name shown in the output instead of the id
+    pub display_name: Option<String>,
+    /// User URI
+    pub id: String,
+    /// Market used to fetch the playlists of this user, overrides the global market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    /// How often `spt watch` fetches the playlists of this user, e.g. `30m`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+    /// Which kinds of playlists of this user are tracked
+    #[serde(default, skip_serializing_if = "PlaylistPolicy::is_default")]
+    pub playlists: PlaylistPolicy,
+    /// Which playlists of this user are tracked, in addition to the global filter
+    #[serde(default, skip_serializing_if = "PlaylistFilter::is_default")]
+    pub filter: PlaylistFilter,
+}
+impl TrackedUser {
+    pub fn name_or_id(&self) -> &str {
+        self.display_name.as_ref().unwrap_or(&self.id)
+    }
+}
+
+/// A playlist tracked on its own, independent of the users, e.g. a playlist of someone who
+/// isn't tracked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct TrackedPlaylist {
+    /// Playlist URI
+    pub id: String,
+    /// Name shown in the output instead of the id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Market used to fetch this playlist, overrides the global market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    /// How often `spt watch` fetches this playlist, e.g. `30m`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+}
+impl TrackedPlaylist {
+    pub fn name_or_id(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.id)
+    }
+}
+
+impl Fields for User {
+    fn fields() -> String {
+        "display_name,external_urls,href,id".to_string()
+    }
+}
+
+/// Kinds of playlists tracked for a user, besides the public playlists owned by the user.
+/// Private and collaborative playlists are only visible for the logged in user and need
+/// the `playlist-read-private` and `playlist-read-collaborative` scopes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PlaylistPolicy {
+    /// Track private playlists owned by the user
+    #[serde(default)]
+    pub private: bool,
+    /// Track collaborative playlists, including the ones owned by other users
+    #[serde(default)]
+    pub collaborative: bool,
+}
+impl PlaylistPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == PlaylistPolicy::default()
+    }
+
+    /// Scopes the login needs to see the playlists of this policy
+    pub fn scopes(&self) -> Vec<&'static str> {
+        let mut scopes = Vec::new();
+        if self.private {
+            scopes.push("playlist-read-private");
+        }
+        if self.collaborative {
+            scopes.push("playlist-read-collaborative");
+        }
+        scopes
+    }
+
+    /// Checks if a playlist listed for the user `user_id` is tracked
+    pub fn tracks(&self, user_id: &str, playlist: &model::SimplifiedPlaylist) -> bool {
+        if playlist.collaborative {
+            return self.collaborative;
+        }
+        // Playlists not owned by the user are followed ones (e.g. the Daily Mix etc.)
+        if playlist.owner.id.to_string() != user_id {
+            return false;
+        }
+        match playlist.public {
+            Some(false) => self.private,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Artist {
     name: String,
     id: Option<String>,
+    /// Link to the artist on open.spotify.com
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_url: Option<String>,
 }
 impl From<model::SimplifiedArtist> for Artist {
-    fn from(item: model::SimplifiedArtist) -> Self {
+    fn from(mut item: model::SimplifiedArtist) -> Self {
         Artist {
             name: item.name,
             id: item.id.map(|id| id.to_string()),
+            external_url: item.external_urls.remove("spotify"),
         }
     }
 }
+impl Artist {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> Option<&String> {
+        self.id.as_ref()
+    }
+    pub fn external_url(&self) -> Option<&str> {
+        self.external_url.as_deref()
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+impl Fields for Artist {
+    fn fields() -> String {
+        "external_urls,id,name".to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Album {
     artists: Vec<Artist>,
     id: Option<String>,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    album_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_date: Option<String>,
+    /// Precision of the release date (`year`, `month` or `day`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_date_precision: Option<String>,
 }
 impl From<model::SimplifiedAlbum> for Album {
     fn from(item: model::SimplifiedAlbum) -> Self {
         Album {
-            artists: item
-                .artists
-                .into_iter()
-                .map(|artist| Artist::from(artist))
-                .collect(),
+            artists: item.artists.into_iter().map(Artist::from).collect(),
             id: item.id.map(|id| id.to_string()),
             name: item.name,
+            album_type: item.album_type,
+            release_date: item.release_date,
+            release_date_precision: item.release_date_precision,
         }
     }
 }
+impl Album {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> Option<&String> {
+        self.id.as_ref()
+    }
+    pub fn artists(&self) -> &[Artist] {
+        &self.artists
+    }
+    pub fn album_type(&self) -> Option<&str> {
+        self.album_type.as_deref()
+    }
+    pub fn release_date(&self) -> Option<&str> {
+        self.release_date.as_deref()
+    }
+    pub fn release_date_precision(&self) -> Option<&str> {
+        self.release_date_precision.as_deref()
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+impl Fields for Album {
+    fn fields() -> String {
+        format!(
+            "album_type,artists({}),external_urls,id,images(url),name,release_date,\
+             release_date_precision",
+            Artist::fields()
+        )
+    }
+}
+
+/// Track as stored in the events.
+///
+/// Everything beyond artists, album, id and name was added later on, so those fields have to
+/// be optional (or defaulted) to keep the events that were stored before deserializable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Track {
     artists: Vec<Artist>,
     album: Album,
     id: Option<String>,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    is_playable: Option<bool>,
+    /// Id of the originally requested track if Spotify relinked it for the market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linked_from: Option<String>,
+    /// Reason why the track is restricted (`market`, `product` or `explicit`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    restrictions: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    popularity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    explicit: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    isrc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disc_number: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    track_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_local: bool,
 }
 impl From<model::FullTrack> for Track {
-    fn from(item: model::FullTrack) -> Self {
+    fn from(mut item: model::FullTrack) -> Self {
         Track {
-            artists: item
-                .artists
-                .into_iter()
-                .map(|artist| Artist::from(artist))
-                .collect(),
+            artists: item.artists.into_iter().map(Artist::from).collect(),
             album: Album::from(item.album),
             id: item.id.map(|id| id.to_string()),
             name: item.name,
+            is_playable: item.is_playable,
+            linked_from: item.linked_from.map(|link| link.id.to_string()),
+            restrictions: item
+                .restrictions
+                .map(|restriction| <&str>::from(restriction.reason).to_string()),
+            duration_ms: u64::try_from(item.duration.num_milliseconds()).ok(),
+            popularity: Some(item.popularity),
+            explicit: Some(item.explicit),
+            isrc: item.external_ids.remove("isrc"),
+            disc_number: Some(item.disc_number),
+            track_number: Some(item.track_number),
+            is_local: item.is_local,
+        }
+    }
+}
+impl Track {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> Option<&String> {
+        self.id.as_ref()
+    }
+    pub fn artists(&self) -> &[Artist] {
+        &self.artists
+    }
+    pub fn album(&self) -> &Album {
+        &self.album
+    }
+    /// Id of the track as it was added to the playlist, independent of market relinking
+    pub fn logical_id(&self) -> Option<&String> {
+        self.linked_from.as_ref().or(self.id.as_ref())
+    }
+    pub fn is_playable(&self) -> Option<bool> {
+        self.is_playable
+    }
+    pub fn restrictions(&self) -> Option<&str> {
+        self.restrictions.as_deref()
+    }
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration_ms
+    }
+    pub fn popularity(&self) -> Option<u32> {
+        self.popularity
+    }
+    pub fn explicit(&self) -> Option<bool> {
+        self.explicit
+    }
+    pub fn isrc(&self) -> Option<&str> {
+        self.isrc.as_deref()
+    }
+    pub fn disc_number(&self) -> Option<i32> {
+        self.disc_number
+    }
+    pub fn track_number(&self) -> Option<u32> {
+        self.track_number
+    }
+    pub fn is_local(&self) -> bool {
+        self.is_local
+    }
+
+    /// Stable identity of the track. Before local files got their own variant they were stored
+    /// as tracks without an id, those get the same key as the matching [`LocalFile`].
+    pub fn key(&self) -> String {
+        match self.logical_id() {
+            Some(id) => id.clone(),
+            None => LocalFile::key_for(
+                self.artists.first().map(|artist| artist.name.as_str()),
+                &self.name,
+            ),
+        }
+    }
+}
+impl std::fmt::Display for Track {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let artists: Vec<&str> = self
+            .artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect();
+        if artists.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} - {}", artists.join(", "), self.name)
         }
     }
 }
 
+impl Fields for Track {
+    fn fields() -> String {
+        format!(
+            "album({}),artists({}),disc_number,duration_ms,explicit,external_ids,external_urls,\
+             id,is_local,is_playable,linked_from(external_urls,href,id),name,popularity,\
+             restrictions,track_number",
+            Album::fields(),
+            Artist::fields()
+        )
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Episode {
     id: String,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    show: Option<Show>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    release_date: Option<String>,
 }
 impl From<model::FullEpisode> for Episode {
     fn from(item: model::FullEpisode) -> Self {
         Episode {
             id: item.id.to_string(),
             name: item.name,
+            show: Some(Show::from(item.show)),
+            duration_ms: u64::try_from(item.duration.num_milliseconds()).ok(),
+            release_date: Some(item.release_date),
+        }
+    }
+}
+impl Episode {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn show(&self) -> Option<&Show> {
+        self.show.as_ref()
+    }
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration_ms
+    }
+    pub fn release_date(&self) -> Option<&str> {
+        self.release_date.as_deref()
+    }
+
+    /// Stable identity of the episode
+    pub fn key(&self) -> String {
+        self.id.clone()
+    }
+}
+impl std::fmt::Display for Episode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.show {
+            Some(show) => write!(f, "{} - {}", show.name, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl Fields for Episode {
+    fn fields() -> String {
+        "description,duration_ms,explicit,external_urls,href,id,images(url),is_externally_hosted,\
+         is_playable,language,languages,name,release_date,release_date_precision,\
+         show(available_markets,copyrights,description,explicit,external_urls,href,id,\
+         images(url),languages,media_type,name,publisher)"
+            .to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Show {
+    id: String,
+    name: String,
+    publisher: String,
+}
+impl From<model::SimplifiedShow> for Show {
+    fn from(item: model::SimplifiedShow) -> Self {
+        Show {
+            id: item.id.to_string(),
+            name: item.name,
+            publisher: item.publisher,
         }
     }
 }
+impl Show {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn publisher(&self) -> &str {
+        &self.publisher
+    }
+}
 
+/// A local file the user added to the playlist. These have no Spotify id, only a
+/// `spotify:local:` URI made up from their metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LocalFile {
+    uri: String,
+    name: String,
+    artist: Option<String>,
+    duration_ms: Option<u64>,
+}
+impl From<model::FullTrack> for LocalFile {
+    fn from(item: model::FullTrack) -> Self {
+        let artist = item.artists.into_iter().next().map(|artist| artist.name);
+        let duration_ms = u64::try_from(item.duration.num_milliseconds()).ok();
+        let uri = format!(
+            "spotify:local:{}:{}:{}:{}",
+            encode_local(artist.as_deref().unwrap_or_default()),
+            encode_local(&item.album.name),
+            encode_local(&item.name),
+            duration_ms.unwrap_or_default() / 1000
+        );
+        LocalFile {
+            uri,
+            name: item.name,
+            artist,
+            duration_ms,
+        }
+    }
+}
+impl LocalFile {
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn artist(&self) -> Option<&str> {
+        self.artist.as_deref()
+    }
+    pub fn duration_ms(&self) -> Option<u64> {
+        self.duration_ms
+    }
+
+    /// Stable identity of the local file.
+    /// Album and duration are left out, older events don't always know them.
+    pub fn key(&self) -> String {
+        LocalFile::key_for(self.artist.as_deref(), &self.name)
+    }
+
+    fn key_for(artist: Option<&str>, name: &str) -> String {
+        format!(
+            "spotify:local:{}:{}",
+            encode_local(artist.unwrap_or_default()),
+            encode_local(name)
+        )
+    }
+}
+impl std::fmt::Display for LocalFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.artist {
+            Some(artist) => write!(f, "{} - {} (local)", artist, self.name),
+            None => write!(f, "{} (local)", self.name),
+        }
+    }
+}
+
+/// Encodes a part of a `spotify:local:` URI the way Spotify does (form url encoding)
+fn encode_local(part: &str) -> String {
+    let mut encoded = String::with_capacity(part.len());
+    for byte in part.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'*' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum PlayableItem {
     Track(Track),
     Episode(Episode),
+    LocalFile(LocalFile),
 }
 impl From<model::PlayableItem> for PlayableItem {
     fn from(item: model::PlayableItem) -> Self {
         match item {
+            model::PlayableItem::Track(track) if track.is_local => {
+                PlayableItem::LocalFile(LocalFile::from(track))
+            }
             model::PlayableItem::Track(track) => PlayableItem::Track(Track::from(track)),
             model::PlayableItem::Episode(epi) => PlayableItem::Episode(Episode::from(epi)),
         }
     }
 }
+impl PlayableItem {
+    /// Stable identity of the item, used to match it across fetches
+    pub fn key(&self) -> String {
+        match self {
+            PlayableItem::Track(track) => track.key(),
+            PlayableItem::Episode(episode) => episode.key(),
+            PlayableItem::LocalFile(file) => file.key(),
+        }
+    }
+}
+impl std::fmt::Display for PlayableItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlayableItem::Track(track) => write!(f, "{}", track),
+            PlayableItem::Episode(episode) => write!(f, "{}", episode),
+            PlayableItem::LocalFile(file) => write!(f, "{}", file),
+        }
+    }
+}
+
+impl Fields for PlayableItem {
+    /// Items are either tracks (local files included) or episodes, so the selector has to
+    /// cover both
+    fn fields() -> String {
+        merge_fields(&[&Track::fields(), &Episode::fields()])
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PlaylistItem {
@@ -183,21 +754,105 @@ impl From<model::PlaylistItem> for PlaylistItem {
     fn from(item: model::PlaylistItem) -> Self {
         PlaylistItem {
             added_at: item.added_at,
-            added_by: item.added_by.map(|user| User::from(user)),
-            track: match item.track {
-                Some(model::PlayableItem::Track(track)) => {
-                    Some(PlayableItem::Track(Track::from(track)))
-                }
-                Some(model::PlayableItem::Episode(episode)) => {
-                    Some(PlayableItem::Episode(Episode::from(episode)))
-                }
-                None => None,
-            },
+            added_by: item.added_by.map(User::from),
+            track: item.track.map(PlayableItem::from),
         }
     }
 }
 
+impl PlaylistItem {
+    pub fn added_at(&self) -> Option<DateTime<Utc>> {
+        self.added_at
+    }
+    pub fn added_by(&self) -> Option<&User> {
+        self.added_by.as_ref()
+    }
+    pub fn track(&self) -> Option<&PlayableItem> {
+        self.track.as_ref()
+    }
+
+    /// Stable identity of the item. Two items are the same if their keys are equal, no matter
+    /// if the metadata, market relinking or availability of the item changed in between.
+    pub fn key(&self) -> ItemKey {
+        ItemKey {
+            added_at: self.added_at,
+            added_by: self.added_by.as_ref().map(|user| user.id.clone()),
+            item: self.track.as_ref().map(PlayableItem::key),
+        }
+    }
+
+    /// Whether the item can be played, `None` if Spotify didn't tell us
+    pub fn is_playable(&self) -> Option<bool> {
+        match &self.track {
+            Some(PlayableItem::Track(track)) => track.is_playable(),
+            _ => None,
+        }
+    }
+
+    /// Reason why the item is restricted, if it is
+    pub fn restrictions(&self) -> Option<&str> {
+        match &self.track {
+            Some(PlayableItem::Track(track)) => track.restrictions(),
+            _ => None,
+        }
+    }
+
+    /// Whether the item is greyed out. Unknown availability counts as available, and so do
+    /// restrictions that only apply to some listeners, like explicit content.
+    pub fn is_unavailable(&self) -> bool {
+        self.is_playable() == Some(false) || self.availability_restriction().is_some()
+    }
+
+    /// Whether both items report the same availability, restrictions that don't make the item
+    /// unavailable are ignored
+    pub fn same_availability(&self, other: &PlaylistItem) -> bool {
+        self.is_playable().unwrap_or(true) == other.is_playable().unwrap_or(true)
+            && self.availability_restriction() == other.availability_restriction()
+    }
+
+    /// The restriction reason if it greys the item out for everyone
+    fn availability_restriction(&self) -> Option<&str> {
+        self.restrictions()
+            .filter(|reason| matches!(*reason, "market" | "product"))
+    }
+}
+impl std::fmt::Display for PlaylistItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.track {
+            Some(item) => write!(f, "{}", item),
+            None => write!(f, "<unknown item>"),
+        }
+    }
+}
+
+/// Identity of a playlist item. The same track can be in a playlist more than once, so when and
+/// by whom it was added is part of the identity.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ItemKey {
+    pub added_at: Option<DateTime<Utc>>,
+    pub added_by: Option<String>,
+    pub item: Option<String>,
+}
+
+/// An item at another position than before, positions start at 0
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MovedItem {
+    pub item: PlaylistItem,
+    pub from: usize,
+    pub to: usize,
+}
+
+impl Fields for PlaylistItem {
+    fn fields() -> String {
+        format!(
+            "added_at,added_by({}),is_local,track({})",
+            User::fields(),
+            PlayableItem::fields()
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub struct PlaylistItems(pub Vec<PlaylistItem>);
 impl std::ops::Deref for PlaylistItems {
     type Target = Vec<PlaylistItem>;
@@ -206,14 +861,56 @@ impl std::ops::Deref for PlaylistItems {
         &self.0
     }
 }
+impl PlaylistItems {
+    /// Items that are in both lists but in another order in `other`. As few items as possible
+    /// are reported, the others keep their order. Sorted by their new position.
+    pub fn moves_to(&self, other: &[PlaylistItem]) -> Vec<MovedItem> {
+        let mut positions: HashMap<ItemKey, VecDeque<usize>> = HashMap::new();
+        for (idx, item) in other.iter().enumerate() {
+            positions.entry(item.key()).or_default().push_back(idx);
+        }
+        // Old and new position of the items in both lists, in the old order
+        let common: Vec<(usize, usize)> = self
+            .iter()
+            .enumerate()
+            .filter_map(|(from, item)| Some((from, positions.get_mut(&item.key())?.pop_front()?)))
+            .collect();
+
+        // The longest run of items whose new positions are still increasing stays in place
+        let mut tails: Vec<usize> = Vec::new();
+        let mut prev: Vec<Option<usize>> = vec![None; common.len()];
+        for (idx, &(_, to)) in common.iter().enumerate() {
+            let len = tails.partition_point(|&tail| common[tail].1 < to);
+            prev[idx] = len.checked_sub(1).map(|len| tails[len]);
+            match tails.get_mut(len) {
+                Some(tail) => *tail = idx,
+                None => tails.push(idx),
+            }
+        }
+        let mut stays = vec![false; common.len()];
+        let mut next = tails.last().copied();
+        while let Some(idx) = next {
+            stays[idx] = true;
+            next = prev[idx];
+        }
+
+        let mut moved: Vec<MovedItem> = common
+            .iter()
+            .zip(stays)
+            .filter(|(_, stays)| !stays)
+            .map(|(&(from, to), _)| MovedItem {
+                item: other[to].clone(),
+                from,
+                to,
+            })
+            .collect();
+        moved.sort_by_key(|moved| moved.to);
+        moved
+    }
+}
 impl From<Vec<model::PlaylistItem>> for PlaylistItems {
     fn from(items: Vec<model::PlaylistItem>) -> Self {
-        PlaylistItems(
-            items
-                .into_iter()
-                .map(|item| PlaylistItem::from(item))
-                .collect(),
-        )
+        PlaylistItems(items.into_iter().map(PlaylistItem::from).collect())
     }
 }
 impl FromIterator<PlaylistItem> for PlaylistItems {
@@ -221,6 +918,16 @@ impl FromIterator<PlaylistItem> for PlaylistItems {
         PlaylistItems(iter.into_iter().collect())
     }
 }
+impl Fields for PlaylistItems {
+    /// Selector for a page of the playlist items endpoint
+    fn fields() -> String {
+        format!(
+            "href,limit,next,offset,previous,total,items({})",
+            PlaylistItem::fields()
+        )
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Playlist {
     pub collaborative: bool,
@@ -249,21 +956,68 @@ impl From<model::FullPlaylist> for Playlist {
     }
 }
 
+impl Fields for Playlist {
+    /// Selector for the playlist endpoint. The tracks are fetched separately through the
+    /// playlist items endpoint, so only a stub of the embedded first page is requested.
+    fn fields() -> String {
+        format!(
+            "collaborative,description,external_urls,followers(total),href,id,images(url),name,\
+             owner({}),public,snapshot_id,tracks(href,limit,next,offset,previous,total,items(is_local))",
+            User::fields()
+        )
+    }
+}
+
 impl Playlist {
-    pub fn from_id(
-        client: &rspotify::AuthCodeSpotify,
+    pub fn from_id<C: BaseClient>(
+        client: &C,
         playlist_id: model::PlaylistId,
-        fields: Option<&str>,
-        market: Option<rspotify::model::Market>,
+        options: &FetchOptions,
     ) -> Result<Playlist, rspotify::ClientError> {
-        let playlist = client.playlist(playlist_id, fields, market)?;
+        let playlist = client.playlist(
+            playlist_id,
+            options.playlist_fields.as_deref(),
+            options.market,
+        )?;
         let tracks: Vec<ClientResult<rspotify::model::PlaylistItem>> = client
-            .playlist_items(playlist.id.clone(), fields, market)
+            .playlist_items(
+                playlist.id.clone(),
+                options.item_fields.as_deref(),
+                options.market,
+            )
             .collect();
         let tracks: ClientResult<Vec<model::PlaylistItem>> = tracks.into_iter().collect();
         let mut playlist = Playlist::from(playlist);
         playlist.tracks = PlaylistItems::from(tracks?);
-        return Ok(playlist);
+        Ok(playlist)
+    }
+
+    /// Fetches the metadata of a playlist in the form the playlist listings of a user have
+    pub fn summary<C: BaseClient>(
+        client: &C,
+        playlist_id: model::PlaylistId,
+        options: &FetchOptions,
+    ) -> Result<model::SimplifiedPlaylist, rspotify::ClientError> {
+        let playlist = client.playlist(
+            playlist_id,
+            options.playlist_fields.as_deref(),
+            options.market,
+        )?;
+        Ok(model::SimplifiedPlaylist {
+            collaborative: playlist.collaborative,
+            external_urls: playlist.external_urls,
+            href: playlist.href,
+            id: playlist.id,
+            images: playlist.images,
+            name: playlist.name,
+            owner: playlist.owner,
+            public: playlist.public,
+            snapshot_id: playlist.snapshot_id,
+            tracks: model::PlaylistTracksRef {
+                href: playlist.tracks.href,
+                total: playlist.tracks.total,
+            },
+        })
     }
 
     /// Creates new empty Playlist
@@ -281,3 +1035,8 @@ impl Playlist {
         }
     }
 }
+impl Default for Playlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}